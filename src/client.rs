@@ -24,16 +24,21 @@ use crate::irc::reply as reply;
 use crate::irc::{self, Core, User, NamedEntity};
 use crate::parser::{parse_message, ParseError};
 use crate::irc::chan::ChanError;
+use std::collections::BTreeSet;
 use std::error;
 use std::fmt;
 use std::io::Error as ioError;
 use std::net::IpAddr;
 use std::sync::{Arc, Weak, Mutex};
+use std::time::Duration;
+use chrono::Utc;
 use log::{debug, warn};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter, Lines};
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::error::SendError as mpscSendErr;
 use tokio::task::JoinError as tokJoinErr;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
 use tokio_native_tls::native_tls::Error as tntTlsErr;
 
 /* There are 3 main types of errors we can have here...
@@ -54,7 +59,8 @@ pub enum GenError {
     DeadClient(Arc<User>),
     DeadUser(String),
     TLS(tntTlsErr),
-    Tokio(tokJoinErr)
+    Tokio(tokJoinErr),
+    Quit(String),
 }
 
 impl fmt::Display for GenError {
@@ -68,7 +74,8 @@ impl fmt::Display for GenError {
             GenError::DeadClient(user) => write!(f, "user {}, stale client", user.get_nick()),
             GenError::DeadUser(nick) => write!(f, "user {}, remant, scattered WeakRefs", nick),
             GenError::TLS(ref err) => write!(f, "TLS Error: {}", err),
-            GenError::Tokio(ref err) => write!(f, "TLS Error: {}", err)
+            GenError::Tokio(ref err) => write!(f, "TLS Error: {}", err),
+            GenError::Quit(reason) => write!(f, "Client quit: {}", reason),
         }
     }
 }
@@ -88,7 +95,8 @@ impl error::Error for GenError {
             GenError::DeadUser(_nick) => None,
             GenError::Chan(ref err) => Some(err),
             GenError::TLS(ref err) => Some(err),
-            GenError::Tokio(ref err) => Some(err)
+            GenError::Tokio(ref err) => Some(err),
+            GenError::Quit(_reason) => None,
         }
     }
 }
@@ -173,29 +181,52 @@ impl Clone for ClientType {
 }
 
 type MsgRecvr = mpsc::Receiver<String>;
+
+/* STATS l figures for one connection, shared with its write task so
+ * that sendq is what's really still waiting to go out */
+#[derive(Debug, Clone, Default)]
+pub struct LinkStats {
+    pub sendq: usize,
+    pub sent_msgs: u64,
+    pub sent_bytes: u64,
+    pub recv_msgs: u64,
+    pub recv_bytes: u64,
+}
 pub type ClientReply = Result<ircReply, ircError>;
 pub type ClientReplies = Vec<ClientReply>;
 
-pub async fn run_write_task(sock: WriteHalfWrap, mut rx: MsgRecvr) -> Result<(), ioError> {
+pub async fn run_write_task(sock: WriteHalfWrap, mut rx: MsgRecvr, stats: Arc<Mutex<LinkStats>>) -> Result<(), ioError> {
     /* apparently we can't have ? after await on any of these
      * functions, because await returns (), but recv() and
      * write_all()/flush() shouldn't return (), should they? */
     let mut stream = BufWriter::new(sock);
     while let Some(msg) = rx.recv().await {
-        stream.write(msg.as_bytes()).await?;
+        stream.write_all(msg.as_bytes()).await?;
         stream.flush().await?;
-    }
+        let mut stats = stats.lock().unwrap();
+        stats.sendq = stats.sendq.saturating_sub(1);
+        stats.sent_msgs += 1;
+        stats.sent_bytes += msg.len() as u64;
+    }
+    /* every sender is gone, so the client is finished with -
+     * say goodbye properly rather than just dropping the socket */
+    stream.shutdown().await?;
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn run_client_handler(
     id: u64,
     host: Host,
+    ip: IpAddr,
     irc: Arc<Core>,
     tx: MsgSendr,
     sock: ReadHalfWrap,
+    class: String,
+    listener: String,
+    stats: Arc<Mutex<LinkStats>>,
 ) {
-    let mut handler = ClientHandler::new(id, host, &irc, tx, sock);
+    let mut handler = ClientHandler::new(id, host, ip, &irc, tx, sock, class, listener, stats);
     irc.insert_client(handler.id, Arc::downgrade(&handler.client));
     debug!("assigned client id {}", handler.id);
 
@@ -214,81 +245,100 @@ pub async fn run_client_handler(
 
     /* the main listener loop doesn't .await for the return
      * of this function, so it doesn't make sense to have any
-     * return value, instead some diagnostics should be printed
-     * here if there is any error */
-    if let Err(err) = res {
-        debug!("Client {} exited with error {}", handler.id, err);
-    } else {
-        debug!("{}", "Unexpected EOF".to_string());
-    }
-    /* All the cleanup stuff should just happen on Drop, so I've commented
-     * a bunch out for now */
-
-    /* whether we had an error or a graceful return,
-     * we need to do some cleanup, namely: remove the client
-     * from the hash table the IRC daemon holds of users/
-     * clients */
-    /*if let ClientType::User(user) = handler.client.get_client_type() {
-        let nick = user.get_nick();
-
-        /* clear them from any leftover channels */
-        let witnesses = user.clear_chans_and_exit();
-    }*/
-/*
-        match irc.remove_name(&nick) {
-            Ok(_name_entity) =>
-                debug!("Exit Client {} - freed user with nick: {}",
-                        handler.id, &nick),
-            Err(err) =>
-                warn!("Exit Client {} - free nick {} failed: {}",
-                        handler.id, &nick, err),
-        }
+     * return value, instead work out why the client is leaving
+     * and let disconnect() deal with it */
+    let reason = match res {
+        Ok(()) => String::from("Remote host closed the connection"),
+        Err(GenError::Quit(reason)) => reason,
+        Err(GenError::Io(err)) => format!("Read error: {}", err),
+        Err(GenError::TLS(err)) => format!("Read error: {}", err),
+        Err(GenError::Mpsc(_err)) => String::from("Write error: connection closed"),
+        Err(err) => {
+            warn!("Client {} exited with unexpected error {}", handler.id, err);
+            String::from("Internal error")
+        },
+    };
+    debug!("Client {} disconnecting: {}", handler.id, reason);
+    handler.client.disconnect(&reason).await;
+}
 
-        /* instead of all this mad stuff it would also be
-         * an option to push to id_list vector and then .sort() and .dedup()
-         */
-        let mut id_list: Vec<u64> = Vec::new();
-        {
-            let mut user_list: BTreeMap<u64, Arc<User>> = BTreeMap::new();
-            for chan in witnesses.iter() {
-                let users = chan.gen_user_ptr_vec().clone();
-                for user in users.iter() {
-                    let id = user.get_id();
-                    user_list.insert(id, Arc::clone(&user));
-                }
-            }
+/* a client gets a burst of `flood` lines, after that it earns one
+ * more line a second - run out and it's an Excess Flood */
+struct FloodGuard {
+    burst: f64,
+    allowance: f64,
+    last: Instant,
+}
 
-            for key in user_list.keys() {
-                id_list.push(*key);
-            }
+impl FloodGuard {
+    fn new(burst: usize) -> Self {
+        FloodGuard {
+            burst: burst as f64,
+            allowance: burst as f64,
+            last: Instant::now(),
         }
+    }
 
-        let line = format!(":{} QUIT :{}", user.get_prefix(), death_reason);
-        for id in id_list.iter() {
-            if *id == handler.id {
-                continue
-            }
-            if let Some(client_weakptr) = irc.get_client(id) {
-                if let Some(client) = Weak::upgrade(&client_weakptr) {
-                    if let Err(err) = client.send_line(&line).await {
-                        debug!("failed to send to client {}: {}", id, err);
-                    }
-                }
-            }
+    fn take_line(&mut self) -> bool {
+        let now = Instant::now();
+        self.allowance += now.duration_since(self.last).as_secs_f64();
+        self.allowance = self.allowance.min(self.burst);
+        self.last = now;
+        if self.allowance < 1.0 {
+            false
+        } else {
+            self.allowance -= 1.0;
+            true
         }
     }
-
-    /* remove self from main irc Client HashMap */
-    if irc.remove_client(&handler.id).is_some() {
-        debug!("successfully removed client {} from IRC core hashmap", id);
-    } else {
-        warn!("attempted removal of our own client {} failed", id);
-    }*/
 }
 
 /* Receive and process IRC messages */
 async fn process_lines(handler: &mut ClientHandler, irc: &Arc<Core>) -> Result<(), GenError> {
-    while let Some(line) = handler.stream.next_line().await? {
+    let mut shutdown = irc.shutdown_watch();
+    let class = irc.get_class(&handler.client.get_class());
+    let mut flood = FloodGuard::new(class.flood);
+    let ping_interval = Duration::from_secs(class.ping_interval);
+    let ping_timeout = Duration::from_secs(class.ping_timeout);
+    /* unregistered clients get one deadline to finish NICK/USER, after
+     * that we PING whenever a client's been quiet for ping_interval, and
+     * anything at all they send back within ping_timeout will do */
+    let register_deadline = Instant::now() + Duration::from_secs(class.register_timeout);
+    let mut last_heard = Instant::now();
+    let mut pinged = false;
+    loop {
+        let deadline = if !handler.client.is_registered() {
+            register_deadline
+        } else if pinged {
+            last_heard + ping_interval + ping_timeout
+        } else {
+            last_heard + ping_interval
+        };
+        let line = tokio::select! {
+            line = handler.stream.next_line() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+            /* not shutdown.changed(), if we only cloned the watch after
+             * the shutdown was sent there's no change left for us to see */
+            reason = irc::shutdown_reason(&mut shutdown) => return Err(GenError::Quit(reason)),
+            _ = time::sleep_until(deadline) => {
+                if !handler.client.is_registered() {
+                    return Err(GenError::Quit(String::from("Registration timed out")));
+                } else if pinged {
+                    return Err(GenError::Quit(format!("Ping timeout: {} seconds", (ping_interval + ping_timeout).as_secs())));
+                }
+                handler.client.send_line(&format!("PING :{}", irc.get_host())).await?;
+                pinged = true;
+                continue;
+            },
+        };
+        last_heard = Instant::now();
+        pinged = false;
+        handler.client.note_received(line.len() + 2);
+        if !flood.take_line() {
+            return Err(GenError::Quit(String::from("Excess Flood")));
+        }
         if line.is_empty() { continue }
         match error_wrapper(&handler.client, irc, &line).await {
             Err(GenError::IRC(err)) => handler.client.send_err(err).await?,
@@ -305,6 +355,7 @@ async fn process_lines(handler: &mut ClientHandler, irc: &Arc<Core>) -> Result<(
             },
             Err(GenError::Tokio(err)) => return Err(GenError::Tokio(err)),
             Err(GenError::TLS(err)) => return Err(GenError::TLS(err)),
+            Err(GenError::Quit(reason)) => return Err(GenError::Quit(reason)),
             Ok(replies) => {
                 for result_t in replies {
                     match result_t {
@@ -323,7 +374,13 @@ async fn process_lines(handler: &mut ClientHandler, irc: &Arc<Core>) -> Result<(
  * IRC errors back to the client, or dropping the client on I/O error */
 async fn error_wrapper (client: &Arc<Client>, irc: &Arc<Core>, line: &str) -> Result<ClientReplies, GenError> {
     let parsed = parse_message(line)?;
-    irc::command(irc, client, parsed).await
+    let cmd = parsed.command.to_ascii_uppercase();
+    let res = irc::command(irc, client, parsed).await;
+    /* STATS m only counts commands we actually know */
+    if !matches!(res, Err(GenError::IRC(ircError::UnknownCommand(_)))) {
+        irc.count_command(&cmd, line.len() + 2);
+    }
+    res
 }
 
 /* found a stale user with no client */
@@ -372,10 +429,22 @@ pub struct ClientHandler {
 }
 
 impl ClientHandler {
-    pub fn new(id: u64, host: Host, irc: &Arc<Core>, tx: MsgSendr, sock: ReadHalfWrap) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: u64,
+        host: Host,
+        ip: IpAddr,
+        irc: &Arc<Core>,
+        tx: MsgSendr,
+        sock: ReadHalfWrap,
+        class: String,
+        listener: String,
+        stats: Arc<Mutex<LinkStats>>,
+    ) -> Self {
+        let tls = matches!(sock, ReadHalfWrap::Encrypted(_));
         ClientHandler {
             stream: BufReader::new(sock).lines(),
-            client: Client::new(id, host, irc, tx),
+            client: Client::new(id, host, ip, tls, irc, tx, class, listener, stats),
             id,
         }
     }
@@ -388,6 +457,19 @@ pub struct Client {
     client_type: Mutex<ClientType>,
     id: u64,
     host: Host,
+    ip: IpAddr,
+    tls: bool,
+    class: String,
+    /* the address of the listener they came in on */
+    listener: String,
+    link_stats: Arc<Mutex<LinkStats>>,
+    connected: i64,
+    /* IRCv3 capabilities this client has asked for, and whether CAP
+     * negotiation is holding up registration */
+    caps: Mutex<BTreeSet<String>>,
+    cap_negotiating: Mutex<bool>,
+    /* LIST output still being sent, see send_list() */
+    list_task: Mutex<Option<JoinHandle<()>>>,
     irc: Arc<Core>,
     tx: MsgSendr,
 }
@@ -398,6 +480,15 @@ impl Clone for Client {
             client_type: Mutex::new(self.client_type.lock().unwrap().clone()),
             id: self.id,
             host: self.host.clone(),
+            ip: self.ip,
+            tls: self.tls,
+            class: self.class.clone(),
+            listener: self.listener.clone(),
+            link_stats: Arc::clone(&self.link_stats),
+            connected: self.connected,
+            caps: Mutex::new(self.caps.lock().unwrap().clone()),
+            cap_negotiating: Mutex::new(*self.cap_negotiating.lock().unwrap()),
+            list_task: Mutex::new(None), /* that one's the original's to finish */
             irc: Arc::clone(&self.irc),
             tx: self.tx.clone(),
         }
@@ -412,11 +503,31 @@ impl Drop for Client {
 }
 
 impl Client {
-    pub fn new(id: u64, host: Host, irc: &Arc<Core>, tx: MsgSendr) -> Arc<Self> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        id: u64,
+        host: Host,
+        ip: IpAddr,
+        tls: bool,
+        irc: &Arc<Core>,
+        tx: MsgSendr,
+        class: String,
+        listener: String,
+        link_stats: Arc<Mutex<LinkStats>>,
+    ) -> Arc<Self> {
         Arc::new(Client {
             client_type: Mutex::new(ClientType::Unregistered),
             id,
             host,
+            ip,
+            tls,
+            class,
+            listener,
+            link_stats,
+            connected: Utc::now().timestamp(),
+            caps: Mutex::new(BTreeSet::new()),
+            cap_negotiating: Mutex::new(false),
+            list_task: Mutex::new(None),
             irc: Arc::clone(irc),
             tx,
         })
@@ -434,6 +545,49 @@ impl Client {
         &self.host
     }
 
+    /* the address they connected from, even if it resolved to a name */
+    pub fn get_ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.lock().unwrap().contains(cap)
+    }
+
+    pub fn get_caps(&self) -> Vec<String> {
+        self.caps.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn set_cap(&self, cap: &str, enable: bool) {
+        let mut caps = self.caps.lock().unwrap();
+        if enable {
+            caps.insert(cap.to_string());
+        } else {
+            caps.remove(cap);
+        }
+    }
+
+    pub fn is_cap_negotiating(&self) -> bool {
+        *self.cap_negotiating.lock().unwrap()
+    }
+
+    pub fn set_cap_negotiating(&self, negotiating: bool) {
+        *self.cap_negotiating.lock().unwrap() = negotiating;
+    }
+
+    /* what goes where the target nick would be, before there is one */
+    pub fn get_nick_or_star(&self) -> String {
+        match self.get_client_type() {
+            ClientType::User(user) => user.get_nick(),
+            ClientType::ProtoUser(proto_user) => proto_user.lock().unwrap().get_nick().unwrap_or_else(|| "*".to_string()),
+            _ => "*".to_string(),
+        }
+    }
+
     pub fn is_registered(&self) -> bool {
         match self.get_client_type() {
             ClientType::Dead => false,
@@ -463,6 +617,30 @@ impl Client {
         self.id
     }
 
+    pub fn get_class(&self) -> String {
+        self.class.clone()
+    }
+
+    pub fn get_listener(&self) -> String {
+        self.listener.clone()
+    }
+
+    pub fn get_link_stats(&self) -> LinkStats {
+        self.link_stats.lock().unwrap().clone()
+    }
+
+    /* the read side counts lines as they come in, CRLF included */
+    pub fn note_received(&self, bytes: usize) {
+        let mut stats = self.link_stats.lock().unwrap();
+        stats.recv_msgs += 1;
+        stats.recv_bytes += bytes as u64;
+    }
+
+    /* when the connection was accepted, as a unix timestamp */
+    pub fn get_connected(&self) -> i64 {
+        self.connected
+    }
+
     pub fn get_irc(&self) -> &Arc<Core> {
         &self.irc
     }
@@ -476,9 +654,15 @@ impl Client {
     }
     
     pub async fn send_rpl(&self, reply: ircReply) -> Result<(), GenError> { /* GDB+ */
+        /* commands that answer by other means return None, nothing to send */
+        if let ircReply::None = reply {
+            return Ok(());
+        }
         /* passing to an async fn and awaiting on it is gonna
          * cause lifetime problems with a &str... */
-        let mut line = reply.format(&self.irc.get_host(), &self.get_user().get_nick());
+        /* not get_user(), the LIST task can get here just after another
+         * worker's disconnect() has marked us Dead */
+        let mut line = reply.format(&self.irc.get_host(), &self.get_nick_or_star());
         /* break up long messages if neccessary,
          * reply::split essentially returns line, None when
          * line is not larger than MAX_MSG_SIZE */
@@ -494,6 +678,48 @@ impl Client {
         Ok(())
     }
 
+    /* LIST output can run to tens of thousands of lines, so it goes out
+     * from a task of its own that waits on the send queue as it goes,
+     * rather than holding up this client's handler (and with it PINGs
+     * and everything else) until the lot's queued - a new LIST replaces
+     * one that's still going */
+    pub fn send_list(self: &Arc<Self>, replies: Vec<ircReply>) {
+        let client = Arc::downgrade(self);
+        let task = tokio::spawn(async move {
+            for reply in replies {
+                let client = match Weak::upgrade(&client) {
+                    Some(client) if client.is_registered() => client,
+                    _ => return,
+                };
+                if let Err(err) = client.send_rpl(reply).await {
+                    debug!("gave up sending LIST to client {}: {}", client.get_id(), err);
+                    return;
+                }
+            }
+        });
+        if let Some(old_task) = self.list_task.lock().unwrap().replace(task) {
+            old_task.abort();
+        }
+    }
+
+    /* every way out ends up here: peers get their QUIT, the user is
+     * gone from the namespace and all channels, and the client itself
+     * gets an ERROR before the write task is left to drain and close */
+    pub async fn disconnect(&self, reason: &str) {
+        if let Some(list_task) = self.list_task.lock().unwrap().take() {
+            list_task.abort();
+        }
+        if let ClientType::User(user) = self.get_client_type() {
+            user.quit(reason).await;
+        }
+        self.set_client_type(ClientType::Dead);
+        let line = format!("ERROR :Closing Link: {} ({})", self.get_host_string(), reason);
+        if let Err(err) = self.send_line(&line).await {
+            debug!("Client {} gone before we could close the link: {}", self.id, err);
+        }
+        self.irc.remove_client(&self.id);
+    }
+
     pub async fn send_line(&self, line: &str) -> Result<(), mpscSendErr<String>> {
         let mut string = String::from(line);
         string.push_str("\r\n");
@@ -501,6 +727,7 @@ impl Client {
          * method, so we don't have to worry about our own
          * Arc/Mutex wrapping, or the problems of holding
          * a mutex across an await */
+        self.link_stats.lock().unwrap().sendq += 1;
         self.tx.clone().send(string).await
     }
 }
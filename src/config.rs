@@ -0,0 +1,457 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+extern crate serde;
+extern crate toml;
+use crate::irc::rfc_defs as rfc;
+use serde::Deserialize;
+use std::env;
+use std::fs;
+use std::io::Error as ioError;
+use std::net::SocketAddr;
+use std::{error, fmt};
+use tokio_native_tls::native_tls::Identity;
+use tokio_native_tls::native_tls::TlsAcceptor as NativeTlsAcc;
+use tokio_native_tls::TlsAcceptor;
+
+/* everything that can go wrong between reading the config file and
+ * having a server we're happy to start - each variant should carry
+ * enough context that the admin knows which bit of the file to fix */
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, ioError),
+    Parse(String, toml::de::Error),
+    Invalid(String),
+    Tls(String, String),
+}
+
+impl error::Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "can't read {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "syntax error in {}: {}", path, err),
+            ConfigError::Invalid(msg) => write!(f, "invalid configuration: {}", msg),
+            ConfigError::Tls(path, msg) => write!(f, "TLS identity {}: {}", path, msg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default, rename = "listen")]
+    pub listeners: Vec<Listen>,
+    #[serde(default, rename = "class")]
+    pub classes: Vec<Class>,
+    #[serde(default, rename = "oper")]
+    pub opers: Vec<Oper>,
+    #[serde(default, rename = "account")]
+    pub accounts: Vec<Account>,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    pub tls: Option<TlsConfig>,
+    pub admin: Option<Admin>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    pub name: String,
+    #[serde(default = "default_network")]
+    pub network: String,
+    #[serde(default)]
+    pub casemapping: rfc::CaseMapping,
+    pub motd: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Limits {
+    pub max_clients: usize,
+    pub nick_len: usize,
+    pub chan_len: usize,
+    pub max_channels: usize,
+    pub max_list_entries: usize,
+    pub invite_expiry: u64,
+    pub knock_delay: u64,
+    pub topic_len: usize,
+    pub whowas_size: usize,
+    pub whowas_expiry: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_clients: 1024,
+            nick_len: 9,
+            chan_len: 50,
+            max_channels: 20,
+            max_list_entries: 50,
+            invite_expiry: 3600,
+            knock_delay: 60,
+            topic_len: 390,
+            whowas_size: 1000,
+            whowas_expiry: 86400,
+        }
+    }
+}
+
+/* what clients are told when we go down (DIE may override the reason),
+ * and how many seconds we give their send queues to empty out */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct Shutdown {
+    pub reason: String,
+    pub drain_timeout: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown {
+            reason: String::from("Server shutting down"),
+            drain_timeout: 5,
+        }
+    }
+}
+
+/* a listener may bring its own TLS identity, otherwise TLS listeners
+ * fall back to the [tls] block; v6_only only means anything for IPv6
+ * addresses, where leaving it false gives a dual-stack socket. The
+ * same goes for the MOTD, which falls back to the [server] one */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Listen {
+    pub address: String,
+    #[serde(default)]
+    pub tls: bool,
+    pub identity: Option<String>,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+    pub password_env: Option<String>,
+    #[serde(default = "default_class_name")]
+    pub class: String,
+    pub v6_only: Option<bool>,
+    #[serde(default = "default_backlog")]
+    pub backlog: i32,
+    pub motd: Option<String>,
+}
+
+/* connection classes group together per-connection settings, every
+ * listener hands its clients one of these */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Class {
+    pub name: String,
+    #[serde(default = "default_sendq")]
+    pub sendq: usize,
+    #[serde(default = "default_flood")]
+    pub flood: usize,
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    #[serde(default = "default_ping_timeout")]
+    pub ping_timeout: u64,
+    #[serde(default = "default_register_timeout")]
+    pub register_timeout: u64,
+    pub max_clients: Option<usize>,
+}
+
+impl Default for Class {
+    fn default() -> Self {
+        Class {
+            name: default_class_name(),
+            sendq: default_sendq(),
+            flood: default_flood(),
+            ping_interval: default_ping_interval(),
+            ping_timeout: default_ping_timeout(),
+            register_timeout: default_register_timeout(),
+            max_clients: None,
+        }
+    }
+}
+
+/* credentials for the OPER command */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Oper {
+    pub name: String,
+    pub password: String,
+}
+
+/* who runs the place, for ADMIN - where, who for, and how to reach them */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Admin {
+    pub location: String,
+    #[serde(default)]
+    pub organisation: String,
+    pub email: String,
+}
+
+/* accounts users can LOGIN to, for +R channels and the like */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Account {
+    pub name: String,
+    pub password: String,
+}
+
+/* the PKCS#12 password may be given inline, read from a file (first
+ * line only), or taken from the environment - exactly one of them */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub identity: String,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+    pub password_env: Option<String>,
+}
+
+fn default_network() -> String {
+    String::from("Rusty")
+}
+
+fn default_class_name() -> String {
+    String::from("default")
+}
+
+fn default_sendq() -> usize {
+    32
+}
+
+fn default_flood() -> usize {
+    20
+}
+
+fn default_ping_interval() -> u64 {
+    120
+}
+
+fn default_ping_timeout() -> u64 {
+    60
+}
+
+fn default_register_timeout() -> u64 {
+    30
+}
+
+fn default_backlog() -> i32 {
+    128
+}
+
+impl Config {
+    /* read, parse and validate in one go, so nobody gets their hands on
+     * a Config that hasn't been checked */
+    pub fn load(path: &str) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| ConfigError::Io(path.to_string(), err))?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|err| ConfigError::Parse(path.to_string(), err))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !rfc::valid_hostname(&self.server.name) {
+            return Err(ConfigError::Invalid(format!("server name `{}` is not a valid hostname", self.server.name)));
+        }
+        if self.server.network.is_empty() || self.server.network.contains(' ') {
+            return Err(ConfigError::Invalid(format!("network name `{}` must be non-empty and contain no spaces", self.server.network)));
+        }
+        if self.limits.nick_len == 0 {
+            return Err(ConfigError::Invalid("limits.nick_len must be at least 1".to_string()));
+        }
+        if self.limits.chan_len < 2 {
+            return Err(ConfigError::Invalid("limits.chan_len must be at least 2".to_string()));
+        }
+        if self.limits.max_list_entries == 0 {
+            return Err(ConfigError::Invalid("limits.max_list_entries must be at least 1".to_string()));
+        }
+        if self.limits.topic_len == 0 {
+            return Err(ConfigError::Invalid("limits.topic_len must be at least 1".to_string()));
+        }
+        if self.limits.invite_expiry == 0 {
+            return Err(ConfigError::Invalid("limits.invite_expiry must be at least 1".to_string()));
+        }
+        if self.limits.max_clients == 0 {
+            return Err(ConfigError::Invalid("limits.max_clients must be at least 1".to_string()));
+        }
+        if self.listeners.is_empty() {
+            return Err(ConfigError::Invalid("no [[listen]] blocks, we'd have nothing to do".to_string()));
+        }
+        for (i, class) in self.classes.iter().enumerate() {
+            if self.classes[..i].iter().any(|c| c.name == class.name) {
+                return Err(ConfigError::Invalid(format!("class `{}` is defined twice", class.name)));
+            }
+            if class.sendq == 0 {
+                return Err(ConfigError::Invalid(format!("class `{}`: sendq must be at least 1", class.name)));
+            }
+            if class.flood == 0 {
+                return Err(ConfigError::Invalid(format!("class `{}`: flood must be at least 1", class.name)));
+            }
+            if class.ping_interval == 0 || class.ping_timeout == 0 || class.register_timeout == 0 {
+                return Err(ConfigError::Invalid(format!("class `{}`: ping_interval, ping_timeout and register_timeout must be at least 1", class.name)));
+            }
+        }
+        for (i, oper) in self.opers.iter().enumerate() {
+            if self.opers[..i].iter().any(|o| o.name == oper.name) {
+                return Err(ConfigError::Invalid(format!("oper `{}` is defined twice", oper.name)));
+            }
+            if oper.password.is_empty() {
+                return Err(ConfigError::Invalid(format!("oper `{}` has an empty password", oper.name)));
+            }
+        }
+        for (i, account) in self.accounts.iter().enumerate() {
+            if self.accounts[..i].iter().any(|a| a.name == account.name) {
+                return Err(ConfigError::Invalid(format!("account `{}` is defined twice", account.name)));
+            }
+            if account.name.is_empty() || account.name.contains(' ') {
+                return Err(ConfigError::Invalid(format!("account name `{}` must be non-empty and contain no spaces", account.name)));
+            }
+            if account.password.is_empty() {
+                return Err(ConfigError::Invalid(format!("account `{}` has an empty password", account.name)));
+            }
+        }
+        for (i, listen) in self.listeners.iter().enumerate() {
+            let addr = listen.socket_addr()?;
+            if self.listeners[..i].iter().any(|l| l.address == listen.address) {
+                return Err(ConfigError::Invalid(format!("listen address {} appears twice", listen.address)));
+            }
+            if listen.v6_only.is_some() && !addr.is_ipv6() {
+                return Err(ConfigError::Invalid(format!("listener {}: v6_only is only for IPv6 addresses", listen.address)));
+            }
+            if listen.backlog < 1 {
+                return Err(ConfigError::Invalid(format!("listener {}: backlog must be at least 1", listen.address)));
+            }
+            if self.get_class(&listen.class).is_none() {
+                return Err(ConfigError::Invalid(format!("listener {} uses undefined class `{}`", listen.address, listen.class)));
+            }
+            match self.listener_tls(listen) {
+                Some(tls) => { tls.get_password()?; },
+                None if listen.tls => return Err(ConfigError::Invalid(
+                    format!("listener {} wants TLS but has no identity and there is no [tls] block", listen.address)
+                )),
+                None => (),
+            }
+            if !listen.tls && listen.identity.is_some() {
+                return Err(ConfigError::Invalid(format!("listener {} has an identity but tls = false", listen.address)));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            tls.get_password()?;
+        }
+        Ok(())
+    }
+
+    /* the "default" class always exists, even if nobody wrote it down */
+    pub fn get_class(&self, name: &str) -> Option<Class> {
+        match self.classes.iter().find(|c| c.name == name) {
+            Some(class) => Some(class.clone()),
+            None if name == default_class_name() => Some(Class::default()),
+            None => None,
+        }
+    }
+
+    pub fn get_listen(&self, address: &str) -> Option<&Listen> {
+        self.listeners.iter().find(|l| l.address == address)
+    }
+
+    /* which identity a listener should present, None for plaintext */
+    pub fn listener_tls(&self, listen: &Listen) -> Option<TlsConfig> {
+        if !listen.tls {
+            None
+        } else if let Some(identity) = &listen.identity {
+            Some(TlsConfig {
+                identity: identity.clone(),
+                password: listen.password.clone(),
+                password_file: listen.password_file.clone(),
+                password_env: listen.password_env.clone(),
+            })
+        } else {
+            self.tls.clone()
+        }
+    }
+
+    /* which MOTD file a listener's clients get, if any */
+    pub fn listener_motd(&self, listen: &Listen) -> Option<String> {
+        listen.motd.clone().or_else(|| self.server.motd.clone())
+    }
+
+    /* the MOTD a listener's clients get, one entry per line of the file,
+     * None if there isn't one configured */
+    pub fn load_motd(&self, listen: &Listen) -> Result<Option<Vec<String>>, ConfigError> {
+        match self.listener_motd(listen) {
+            Some(path) => {
+                let text = fs::read_to_string(&path)
+                    .map_err(|err| ConfigError::Io(path.clone(), err))?;
+                Ok(Some(text.lines().map(str::to_string).collect()))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /* only build an acceptor for listeners that are going to use it */
+    pub fn tls_acceptor(&self, listen: &Listen) -> Result<Option<TlsAcceptor>, ConfigError> {
+        match self.listener_tls(listen) {
+            Some(tls) => Ok(Some(tls.load_acceptor()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Listen {
+    pub fn socket_addr(&self) -> Result<SocketAddr, ConfigError> {
+        self.address.parse::<SocketAddr>().map_err(|err| {
+            ConfigError::Invalid(format!("listen address `{}`: {}", self.address, err))
+        })
+    }
+}
+
+impl TlsConfig {
+    pub fn get_password(&self) -> Result<String, ConfigError> {
+        match (&self.password, &self.password_file, &self.password_env) {
+            (Some(pass), None, None) => Ok(pass.clone()),
+            (None, Some(path), None) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|err| ConfigError::Io(path.clone(), err))?;
+                Ok(text.lines().next().unwrap_or("").to_string())
+            },
+            (None, None, Some(var)) => env::var(var).map_err(|err| {
+                ConfigError::Invalid(format!("tls.password_env `{}`: {}", var, err))
+            }),
+            (None, None, None) => Err(ConfigError::Invalid(
+                "[tls] needs one of password, password_file or password_env".to_string()
+            )),
+            _ => Err(ConfigError::Invalid(
+                "[tls] takes only one of password, password_file or password_env".to_string()
+            )),
+        }
+    }
+
+    pub fn load_acceptor(&self) -> Result<TlsAcceptor, ConfigError> {
+        let der = fs::read(&self.identity)
+            .map_err(|err| ConfigError::Io(self.identity.clone(), err))?;
+        let identity = Identity::from_pkcs12(&der, &self.get_password()?)
+            .map_err(|err| ConfigError::Tls(self.identity.clone(), format!("{} (check password?)", err)))?;
+        let acceptor = NativeTlsAcc::new(identity)
+            .map_err(|err| ConfigError::Tls(self.identity.clone(), err.to_string()))?;
+        Ok(TlsAcceptor::from(acceptor))
+    }
+}
@@ -14,25 +14,42 @@
 *  You should have received a copy of the GNU Lesser General Public License
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
+pub mod ban;
 pub mod chan;
 pub mod error;
+pub mod list;
+pub mod mode;
 pub mod reply;
 pub mod rfc_defs;
+pub mod who;
+pub mod whowas;
 use crate::{USER_MODES, CHAN_MODES};
 use crate::client;
+use crate::config::{Admin, Class, Config, Limits, Listen, Shutdown};
 use crate::client::{Client, ClientType, ClientReply, ClientReplies, GenError, Host};
-use crate::irc::chan::{ChanFlags, Channel, ChanTopic};
+use crate::irc::chan::{ChanFlags, Channel};
+use crate::irc::list::ListFilter;
+use crate::irc::mode::ModeChange;
 use crate::irc::error::Error as ircError;
 use crate::irc::reply::Reply as ircReply;
 use crate::irc::rfc_defs as rfc;
+use crate::irc::who::WhoQuery;
+use crate::irc::whowas::{WhowasEntry, WhowasRing};
 use crate::parser::ParsedMsg;
 extern crate log;
 extern crate chrono;
-use chrono::Utc;
-use log::{debug, warn, trace};
+use chrono::{TimeZone, Utc};
+use log::{debug, info, warn, trace};
 use std::clone::Clone;
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+use tokio::task;
+use tokio::time;
+use tokio_native_tls::TlsAcceptor;
 
 
 macro_rules! gef {
@@ -48,15 +65,18 @@ pub enum NamedEntity {
 impl Clone for NamedEntity {
     fn clone(&self) -> Self {
         match self {
-            NamedEntity::User(ptr) => NamedEntity::User(Weak::clone(&ptr)),
-            NamedEntity::Chan(ptr) => NamedEntity::Chan(Arc::clone(&ptr)),
+            NamedEntity::User(ptr) => NamedEntity::User(Weak::clone(ptr)),
+            NamedEntity::Chan(ptr) => NamedEntity::Chan(Arc::clone(ptr)),
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct UserFlags {
-    registered: bool
+    #[allow(dead_code)]
+    registered: bool,
+    oper: bool,
+    invisible: bool,
 }
 
 #[derive(Debug)]
@@ -66,9 +86,17 @@ pub struct User {
     username: String,
     real_name: Mutex<String>,
     host: Host,
+    ip: IpAddr,
+    tls: bool,
     server: String,
     channel_list: Mutex<HashMap<String, Weak<Channel>>>,
     flags: Mutex<UserFlags>,
+    account: Mutex<Option<String>>,
+    last_knock: Mutex<i64>,
+    /* when they registered, and when they last said anything, for the
+     * idle time WHOIS and WHO give out */
+    signon: i64,
+    last_active: Mutex<i64>,
     irc: Arc<Core>,
     client: Weak<Client>,
 }
@@ -81,9 +109,15 @@ impl Clone for User {
             username: self.username.clone(),
             real_name: Mutex::new(self.real_name.lock().unwrap().clone()),
             host: self.host.clone(),
+            ip: self.ip,
+            tls: self.tls,
             server: self.server.clone(),
             channel_list: Mutex::new(self.channel_list.lock().unwrap().clone()),
             flags: Mutex::new(self.flags.lock().unwrap().clone()),
+            account: Mutex::new(self.account.lock().unwrap().clone()),
+            last_knock: Mutex::new(*self.last_knock.lock().unwrap()),
+            signon: self.signon,
+            last_active: Mutex::new(*self.last_active.lock().unwrap()),
             irc: Arc::clone(&self.irc),
             client: Weak::clone(&self.client)
         }
@@ -98,6 +132,7 @@ impl Drop for User {
 }
 
 impl User {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: u64,
         irc: &Arc<Core>,
@@ -105,29 +140,37 @@ impl User {
         username: String,
         real_name: String,
         host: client::Host,
+        ip: IpAddr,
+        tls: bool,
         server: String,
         client: &Arc<Client>,
     ) -> Arc<Self> {
         Arc::new(User {
             id,
-            irc: Arc::clone(&irc),
+            irc: Arc::clone(irc),
             nick: Mutex::new(nick),
             username,
             real_name: Mutex::new(real_name),
             host,
+            ip,
+            tls,
             server,
             channel_list: Mutex::new(HashMap::new()),
             client: Arc::downgrade(client),
-            flags: Mutex::new(UserFlags { registered: true }), /*channel_list: Mutex::new(Vec::new())*/
+            account: Mutex::new(None),
+            last_knock: Mutex::new(0),
+            signon: Utc::now().timestamp(),
+            last_active: Mutex::new(Utc::now().timestamp()),
+            flags: Mutex::new(UserFlags { registered: true, oper: false, invisible: false }), /*channel_list: Mutex::new(Vec::new())*/
         })
     }
 
     /* since this is basically the drop() code,
      * have drop just call this */
     pub fn clear_up(&self) {
-        self.channel_list.lock()
-            .unwrap()
-            .drain()
+        /* let go of our channel list before locking any channel's */
+        let chans: Vec<_> = self.channel_list.lock().unwrap().drain().collect();
+        chans.into_iter()
             .filter_map(|(_name, chan_ptr)|{
                 Weak::upgrade(&chan_ptr)
                 /* but is it bad to silently ignore the refs that won't upgrade... */
@@ -139,11 +182,68 @@ impl User {
                     }
                 }
             });
-        if let Err(err) = self.irc.remove_name(&self.get_nick()) {
-            warn!("error {} removing non-existant nick {}", err, &self.get_nick());
+        /* by the time drop() gets here quit() has usually freed the nick
+         * already, and somebody else may have taken it since */
+        self.irc.remove_user_name(self);
+    }
+
+    /* same deal as Channel::take_knock(), but for the one knocking, and
+     * split in two so a knock the channel turns away doesn't count */
+    pub fn may_knock(&self, now: i64, delay: i64) -> bool {
+        now - *self.last_knock.lock().unwrap() >= delay
+    }
+
+    pub fn note_knock(&self, now: i64) {
+        *self.last_knock.lock().unwrap() = now;
+    }
+
+    pub fn get_signon(&self) -> i64 {
+        self.signon
+    }
+
+    /* only talking counts, PINGs and the like don't make you any less idle */
+    pub fn mark_active(&self) {
+        *self.last_active.lock().unwrap() = Utc::now().timestamp();
+    }
+
+    pub fn get_idle(&self) -> i64 {
+        Utc::now().timestamp() - *self.last_active.lock().unwrap()
+    }
+
+    /* whether we're on any channel together, for what +i hides */
+    pub fn shares_channel(&self, other: &User) -> bool {
+        self.get_channel_list()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .any(|chan| chan.is_joined(&other.get_nick()))
+    }
+
+    /* the one way out for a registered user, whatever the reason - every
+     * peer on a shared channel sees a single QUIT, then the user is taken
+     * off all their channels and out of the namespace */
+    pub async fn quit(&self, reason: &str) {
+        self.irc.record_whowas(self);
+        self.notify_peers(&format!(":{} QUIT :{}", self.get_prefix(), reason)).await;
+        self.clear_up();
+    }
+
+    /* send a line once to everyone sharing at least one channel with us */
+    pub async fn notify_peers(&self, line: &str) {
+        let mut notified = HashSet::new();
+        for chan in self.get_channel_list().iter().filter_map(Weak::upgrade) {
+            chan.notify_peers(self, line, &mut notified).await;
         }
     }
 
+    /* the first channel we're on that won't let us change nick */
+    pub fn nick_change_blocked(&self) -> Option<String> {
+        self.get_channel_list()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|chan| chan.blocks_nick_change(self))
+            .map(|chan| chan.get_name())
+    }
+
     /* attempt to find and upgrade a pointer to the user's client,
      * if that fails, so some cleanup and return an error indicating
      * dead client or similar */
@@ -153,7 +253,7 @@ impl User {
             debug!("fetch_client(): got a dead client @ user {}", self.get_nick());
             /* can't iterate here as chan.notify_quit() will call
              * user.send_line() and make this fn recursive */
-            GenError::DeadClient(Arc::clone(&self))
+            GenError::DeadClient(Arc::clone(self))
         })
     }
 
@@ -168,14 +268,59 @@ impl User {
         self.id
     }
 
+    pub fn is_oper(&self) -> bool {
+        self.flags.lock().unwrap().oper
+    }
+
+    pub fn set_oper(&self, oper: bool) {
+        self.flags.lock().unwrap().oper = oper;
+    }
+
+    pub fn get_account(&self) -> Option<String> {
+        self.account.lock().unwrap().clone()
+    }
+
+    pub fn set_account(&self, account: Option<String>) {
+        *self.account.lock().unwrap() = account;
+    }
+
+    /* a dead client has no capabilities left to speak of */
+    pub fn has_cap(&self, cap: &str) -> bool {
+        Weak::upgrade(&self.client).is_some_and(|client| client.has_cap(cap))
+    }
+
+    pub fn is_invisible(&self) -> bool {
+        self.flags.lock().unwrap().invisible
+    }
+
+    pub fn set_invisible(&self, invisible: bool) {
+        self.flags.lock().unwrap().invisible = invisible;
+    }
+
+    pub fn get_mode_string(&self) -> String {
+        let flags = self.flags.lock().unwrap();
+        let mut modes = String::from("+");
+        if flags.invisible {
+            modes.push('i');
+        }
+        if flags.oper {
+            modes.push('o');
+        }
+        modes
+    }
+
     pub fn get_channel_list(&self) -> Vec<Weak<Channel>> {
         let mut values = Vec::new();
         for val in self.channel_list.lock().unwrap().values() {
-            values.push(Weak::clone(&val));
+            values.push(Weak::clone(val));
         }
         values
     }
 
+    pub fn get_n_channels(&self) -> usize {
+        self.channel_list.lock().unwrap().len()
+    }
+
     pub fn get_nick(&self) -> String {
         self.nick.lock().unwrap().clone()
     }
@@ -198,6 +343,14 @@ impl User {
         }
     }
 
+    pub fn get_ip(&self) -> IpAddr {
+        self.ip
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.tls
+    }
+
     pub fn get_realname(&self) -> String {
         self.real_name.lock().unwrap().clone()
     }
@@ -272,7 +425,7 @@ impl User {
     }
 
     pub fn upgrade(weak_ptr: &Weak<Self>, nick: &str) -> Result<Arc<Self>, GenError> { /* GDB+++ */
-        if let Some(good_ptr) = Weak::upgrade(&weak_ptr) {
+        if let Some(good_ptr) = Weak::upgrade(weak_ptr) {
             Ok(good_ptr)
         } else {
             Err(GenError::DeadUser(nick.to_string()))
@@ -287,32 +440,106 @@ pub struct ProtoUser {
     real_name: Option<String>,
 }
 
+impl ProtoUser {
+    pub fn get_nick(&self) -> Option<String> {
+        self.nick.clone()
+    }
+}
+
+/* the IRCv3 capabilities CAP LS offers, all we have is what NAMES needs */
+pub const CAPABILITIES: [&str; 2] = ["multi-prefix", "userhost-in-names"];
+
+/* Some(reason) once somebody has asked us to shut down */
+pub type ShutdownWatch = watch::Receiver<Option<String>>;
+
+pub async fn shutdown_reason(watch: &mut ShutdownWatch) -> String {
+    loop {
+        if let Some(reason) = watch.borrow().clone() {
+            return reason;
+        }
+        if watch.changed().await.is_err() {
+            return Shutdown::default().reason;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RehashReport {
+    pub changed: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+impl RehashReport {
+    pub fn log(&self) {
+        for line in self.changed.iter() {
+            info!("rehash: {}", line);
+        }
+        for line in self.failed.iter() {
+            warn!("rehash: {}", line);
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Core {
     namespace: Mutex<HashMap<String, NamedEntity>>,
     clients: Mutex<HashMap<u64, Weak<Client>>>,
-    id_counter: Mutex<u64>, //servers: Mutex<HashMap<u64, Arc<Server>>>,
+    id_counter: Mutex<u64>,
+    max_users: Mutex<usize>, //servers: Mutex<HashMap<u64, Arc<Server>>>,
     hostname: String,
+    casemapping: rfc::CaseMapping,
+    config_path: String,
+    config: Mutex<Config>,
+    listeners: Vec<Listen>,
+    acceptors: Mutex<HashMap<String, Arc<TlsAcceptor>>>,
+    motds: Mutex<HashMap<String, Arc<Vec<String>>>>,
+    shutdown: watch::Sender<Option<String>>,
+    shutdown_rx: ShutdownWatch,
+    drain_guard: Mutex<Option<mpsc::Sender<()>>>,
+    drain_wait: Mutex<Option<mpsc::Receiver<()>>>,
     version: String,
     date: String,
+    started: i64,
+    /* STATS m - how many times each command's been used, and how many
+     * bytes of client input that came to */
+    command_stats: Mutex<BTreeMap<String, (u64, u64)>>,
+    whowas: Mutex<WhowasRing>,
     user_modes: String,
     chan_modes: String
 }
 
 impl Core {
     // init hash tables
-    pub fn new(hostname: String, version: String) -> Arc<Self> {
+    pub fn new(config_path: &str, config: Config, version: String) -> Arc<Self> {
         let clients = Mutex::new(HashMap::new());
         //let servers  = Mutex::new(HashMap::new());
         let namespace = Mutex::new(HashMap::new());
         let id_counter = Mutex::new(0);
+        let (shutdown, shutdown_rx) = watch::channel(None);
+        /* nothing is ever sent on this, every write task holds a clone of
+         * the sender and recv() returns None once they've all finished */
+        let (drain_guard, drain_wait) = mpsc::channel(1);
         Arc::new(Core {
             clients,
             namespace, // combined nick and channel HashMap
             id_counter, //servers
-            hostname,
+            max_users: Mutex::new(0),
+            hostname: config.server.name.clone(),
+            casemapping: config.server.casemapping,
+            config_path: config_path.to_string(),
+            listeners: config.listeners.clone(), // the ones main() binds
+            config: Mutex::new(config),
+            acceptors: Mutex::new(HashMap::new()),
+            motds: Mutex::new(HashMap::new()),
+            shutdown,
+            shutdown_rx,
+            drain_guard: Mutex::new(Some(drain_guard)),
+            drain_wait: Mutex::new(Some(drain_wait)),
             version,
             date: Utc::now().to_rfc2822(),
+            started: Utc::now().timestamp(),
+            command_stats: Mutex::new(BTreeMap::new()),
+            whowas: Mutex::new(WhowasRing::default()),
             user_modes: String::from(USER_MODES),
             chan_modes: String::from(CHAN_MODES)
         })
@@ -328,10 +555,20 @@ impl Core {
         self.clients.lock().unwrap().insert(id, client);
     }
 
+    /* the namespace, channel member lists and users' channel lists are all
+     * keyed by this, the name as given is kept for display */
+    pub fn casefold(&self, name: &str) -> String {
+        self.casemapping.fold(name)
+    }
+
+    pub fn get_casemapping(&self) -> rfc::CaseMapping {
+        self.casemapping
+    }
+
     pub fn insert_name(&self, name: &str, item: NamedEntity) -> Result<(), ircError> {
         let mut hashmap = self.namespace.lock().unwrap();
-        if !hashmap.contains_key(name) {
-            hashmap.insert(name.to_string(), item);
+        if let Entry::Vacant(e) = hashmap.entry(self.casefold(name)) {
+            e.insert(item);
             debug!("added key {} hashmap, size = {}", name, hashmap.len());
             Ok(())
         } else {
@@ -342,7 +579,7 @@ impl Core {
     pub fn remove_name(&self, name: &str) -> Result<NamedEntity, ircError> {
         let mut hashmap = self.namespace.lock().unwrap();
         let ret = hashmap
-            .remove(name)
+            .remove(&self.casefold(name))
             .ok_or_else(|| ircError::NoSuchNick(name.to_string()));
         if ret.is_ok() {
             debug!("removed key {} from hashmap, size = {}", name, hashmap.len());
@@ -350,16 +587,264 @@ impl Core {
         ret
     }
 
+    /* only free the nick if it still belongs to this user, compare
+     * pointers rather than upgrading, drop() calls us once it's too late
+     * to upgrade */
+    pub fn remove_user_name(&self, user: &User) -> bool {
+        let nick = user.get_nick();
+        let key = self.casefold(&nick);
+        let mut hashmap = self.namespace.lock().unwrap();
+        match hashmap.get(&key) {
+            Some(NamedEntity::User(ptr)) if std::ptr::eq(ptr.as_ptr(), user) => {
+                hashmap.remove(&key);
+                debug!("removed key {} from hashmap, size = {}", nick, hashmap.len());
+                true
+            },
+            _ => false,
+        }
+    }
+
     pub fn get_host(&self) -> String {
         self.hostname.clone()
     }
 
+    pub fn get_network(&self) -> String {
+        self.config.lock().unwrap().server.network.clone()
+    }
+
+    pub fn get_limits(&self) -> Limits {
+        self.config.lock().unwrap().limits.clone()
+    }
+
+    pub fn get_config_path(&self) -> String {
+        self.config_path.clone()
+    }
+
+    /* listeners look their class up afresh for every connection,
+     * so a REHASH can move a listener to a different class */
+    pub fn get_listen_class(&self, address: &str) -> Class {
+        let config = self.config.lock().unwrap();
+        config.get_listen(address)
+            .and_then(|listen| config.get_class(&listen.class))
+            .unwrap_or_default()
+    }
+
+    pub fn get_class(&self, name: &str) -> Class {
+        self.config.lock().unwrap().get_class(name).unwrap_or_default()
+    }
+
+    pub fn check_oper(&self, name: &str, password: &str) -> bool {
+        self.config.lock().unwrap().opers
+            .iter()
+            .any(|oper| oper.name == name && oper.password == password)
+    }
+
+    /* hands back the account's name as written in the config */
+    pub fn check_account(&self, name: &str, password: &str) -> Option<String> {
+        self.config.lock().unwrap().accounts
+            .iter()
+            .find(|account| account.name.eq_ignore_ascii_case(name) && account.password == password)
+            .map(|account| account.name.clone())
+    }
+
+    /* just the names, the passwords stay where they are */
+    pub fn get_oper_names(&self) -> Vec<String> {
+        self.config.lock().unwrap().opers
+            .iter()
+            .map(|oper| oper.name.clone())
+            .collect()
+    }
+
+    pub fn get_admin(&self) -> Option<Admin> {
+        self.config.lock().unwrap().admin.clone()
+    }
+
+    pub fn get_shutdown_config(&self) -> Shutdown {
+        self.config.lock().unwrap().shutdown.clone()
+    }
+
+    /* first caller's reason wins, asking twice changes nothing */
+    pub fn shutdown(&self, reason: &str) {
+        if self.shutdown_rx.borrow().is_none() {
+            info!("shutting down: {}", reason);
+            let _res = self.shutdown.send(Some(reason.to_string()));
+        }
+    }
+
+    pub fn shutdown_watch(&self) -> ShutdownWatch {
+        self.shutdown_rx.clone()
+    }
+
+    pub async fn wait_for_shutdown(&self) -> String {
+        shutdown_reason(&mut self.shutdown_watch()).await
+    }
+
+    /* None once we're shutting down, so no new write tasks can start */
+    pub fn get_drain_guard(&self) -> Option<mpsc::Sender<()>> {
+        self.drain_guard.lock().unwrap().clone()
+    }
+
+    /* wait for every client's write task to empty its queue and finish,
+     * up to the timeout - returns false if we gave up waiting */
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        self.drain_guard.lock().unwrap().take();
+        let drain_wait = self.drain_wait.lock().unwrap().take();
+        match drain_wait {
+            Some(mut rx) => time::timeout(timeout, rx.recv()).await.is_ok(),
+            None => true,
+        }
+    }
+
+    pub fn set_acceptor(&self, address: &str, acceptor: TlsAcceptor) {
+        self.acceptors.lock().unwrap().insert(address.to_string(), Arc::new(acceptor));
+    }
+
+    pub fn get_acceptor(&self, address: &str) -> Option<Arc<TlsAcceptor>> {
+        self.acceptors.lock().unwrap().get(address).cloned()
+    }
+
+    /* MOTDs are kept per listener like the acceptors, None takes it away */
+    pub fn set_motd(&self, address: &str, motd: Option<Vec<String>>) {
+        let mut motds = self.motds.lock().unwrap();
+        match motd {
+            Some(lines) => motds.insert(address.to_string(), Arc::new(lines)),
+            None => motds.remove(address),
+        };
+    }
+
+    pub fn get_motd(&self, address: &str) -> Option<Arc<Vec<String>>> {
+        self.motds.lock().unwrap().get(address).cloned()
+    }
+
+    /* re-read the config file and swap in whatever can be changed on the
+     * fly - TLS identities and MOTDs for the listeners we already have, limits,
+     * classes, opers and the network name. Anything that needs a restart
+     * (new listeners, the server name) is reported as a failure and the
+     * running value is kept. Connected clients aren't touched. All the
+     * file reading is done off the runtime, like the DNS lookups. */
+    pub async fn rehash(self: &Arc<Self>) -> RehashReport {
+        let irc = Arc::clone(self);
+        task::spawn_blocking(move || irc.rehash_blocking())
+            .await
+            .unwrap_or_else(|err| RehashReport {
+                failed: vec![format!("rehash didn't finish: {}", err)],
+                ..Default::default()
+            })
+    }
+
+    fn rehash_blocking(&self) -> RehashReport {
+        let mut report = RehashReport::default();
+        let mut new = match Config::load(&self.config_path) {
+            Ok(config) => config,
+            Err(err) => {
+                report.failed.push(format!("{}, keeping the running configuration", err));
+                return report;
+            }
+        };
+        let old = self.config.lock().unwrap().clone();
+
+        if new.server.name != old.server.name {
+            report.failed.push(format!("server name can't change from {} to {} without a restart", old.server.name, new.server.name));
+            new.server.name = old.server.name.clone();
+        }
+        if new.server.casemapping != old.server.casemapping {
+            report.failed.push(format!("casemapping can't change from {} to {} without a restart", old.server.casemapping.name(), new.server.casemapping.name()));
+            new.server.casemapping = old.server.casemapping;
+        }
+        if new.server.network != old.server.network {
+            report.changed.push(format!("network name changed from {} to {}", old.server.network, new.server.network));
+        }
+        if new.limits != old.limits {
+            report.changed.push("limits updated".to_string());
+        }
+        if new.classes != old.classes {
+            report.changed.push("connection classes updated".to_string());
+        }
+        if new.opers != old.opers {
+            report.changed.push("oper blocks updated".to_string());
+        }
+        if new.accounts != old.accounts {
+            report.changed.push("accounts updated".to_string());
+        }
+        if new.shutdown != old.shutdown {
+            report.changed.push("shutdown settings updated".to_string());
+        }
+        if new.admin != old.admin {
+            report.changed.push("admin info updated".to_string());
+        }
+
+        for bound in self.listeners.iter() {
+            match new.get_listen(&bound.address) {
+                None => report.failed.push(format!("listener {} was removed but stays open until restart", bound.address)),
+                Some(listen) if listen.tls != bound.tls => report.failed.push(format!("listener {} can't switch TLS on or off until restart", bound.address)),
+                Some(listen) if listen.tls => match new.tls_acceptor(listen) {
+                    Ok(Some(acceptor)) => {
+                        self.set_acceptor(&listen.address, acceptor);
+                        report.changed.push(format!("reloaded TLS identity for {}", listen.address));
+                    },
+                    Ok(None) => (),
+                    Err(err) => report.failed.push(format!("listener {}: {}, keeping the old identity", listen.address, err)),
+                },
+                Some(_listen) => (),
+            }
+            if let Some(listen) = new.get_listen(&bound.address) {
+                match new.load_motd(listen) {
+                    Ok(Some(motd)) => {
+                        self.set_motd(&listen.address, Some(motd));
+                        report.changed.push(format!("reloaded MOTD for {}", listen.address));
+                    },
+                    Ok(None) => self.set_motd(&listen.address, None),
+                    Err(err) => report.failed.push(format!("MOTD for {}: {}, keeping the old one", listen.address, err)),
+                }
+            }
+        }
+        for listen in new.listeners.iter() {
+            if !self.listeners.iter().any(|bound| bound.address == listen.address) {
+                report.failed.push(format!("new listener {} won't be bound until restart", listen.address));
+            }
+        }
+
+        *self.config.lock().unwrap() = new;
+        report
+    }
+
+    pub fn get_n_clients(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /* don't upgrade while holding the lock, if we end up holding the
+     * last Arc then Client::drop() will want the lock too */
+    pub fn get_n_clients_in_class(&self, class: &str) -> usize {
+        let clients = self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        clients.iter()
+            .filter_map(Weak::upgrade)
+            .filter(|client| client.get_class() == class)
+            .count()
+    }
+
+    /* every connection, registered or not, same deal with the lock as
+     * get_n_clients_in_class() */
+    pub fn list_clients_ptr(&self) -> Vec<Arc<Client>> {
+        let clients = self.clients
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        clients.iter().filter_map(Weak::upgrade).collect()
+    }
+
     pub fn get_client(&self, id: &u64) -> Option<Weak<Client>> {
         self.clients
             .lock()
             .unwrap()
             .get(id)
-            .map(|cli| Weak::clone(cli))
+            .map(Weak::clone)
     }
 
     pub fn remove_client(&self, id: &u64) -> Option<Weak<Client>> {
@@ -367,7 +852,7 @@ impl Core {
     }
 
     pub fn get_name(&self, name: &str) -> Option<NamedEntity> {
-        self.namespace.lock().unwrap().get(name).cloned()
+        self.namespace.lock().unwrap().get(&self.casefold(name)).cloned()
     }
 
     pub fn get_nick(&self, nick: &str) -> Option<Weak<User>> {
@@ -394,12 +879,46 @@ impl Core {
         self.date.clone()
     }
 
+    /* remember who someone was, just before they stop being it */
+    pub fn record_whowas(&self, user: &User) {
+        let limits = self.get_limits();
+        let now = Utc::now().timestamp();
+        let entry = WhowasEntry::new(user, self.casefold(&user.get_nick()), now);
+        self.whowas.lock().unwrap().add(entry, limits.whowas_size, now - limits.whowas_expiry as i64);
+    }
+
+    pub fn find_whowas(&self, nick: &str, count: usize) -> Vec<WhowasEntry> {
+        let expires_before = Utc::now().timestamp() - self.get_limits().whowas_expiry as i64;
+        self.whowas.lock().unwrap().find(&self.casefold(nick), count, expires_before)
+    }
+
+    /* seconds since we started */
+    pub fn get_uptime(&self) -> i64 {
+        Utc::now().timestamp() - self.started
+    }
+
+    pub fn count_command(&self, cmd: &str, bytes: usize) {
+        let mut stats = self.command_stats.lock().unwrap();
+        let entry = stats.entry(cmd.to_string()).or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += bytes as u64;
+    }
+
+    pub fn get_command_stats(&self) -> Vec<(String, u64, u64)> {
+        self.command_stats
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(cmd, (count, bytes))| (cmd.clone(), *count, *bytes))
+            .collect()
+    }
+
     pub fn list_chans_ptr(&self) -> Vec<Arc<Channel>> {
         let mutex_lock = self.namespace.lock().unwrap();
         let mut ret = Vec::new();
         for ent in mutex_lock.values() {
             if let NamedEntity::Chan(chan) = ent {
-                ret.push(Arc::clone(&chan));
+                ret.push(Arc::clone(chan));
             }
         }
         ret
@@ -413,12 +932,134 @@ impl Core {
         }; ret
     }
 
-    pub fn get_list_reply(&self) -> Vec<(Arc<Channel>, Option<ChanTopic>)> {
-        let vector = self.list_chans_ptr();
-        let mut out_vect = Vec::new();
-        for item in vector {
-            out_vect.push((Arc::clone(&item), item.get_topic()));
-        } out_vect
+    /* RPL_ISUPPORT tokens, built from the config as it stands so a
+     * REHASH shows up for anyone who registers (or asks) afterwards -
+     * TARGMAX entries with no number mean there's no limit */
+    pub fn get_isupport(&self) -> Vec<String> {
+        let limits = self.get_limits();
+        vec![
+            format!("CASEMAPPING={}", self.casemapping.name()),
+            format!("CHANLIMIT={}:{}", rfc::CHANTYPES, limits.max_channels),
+            mode::chanmodes_token(&self.chan_modes),
+            format!("CHANNELLEN={}", limits.chan_len),
+            format!("CHANTYPES={}", rfc::CHANTYPES),
+            format!("ELIST={}", list::ELIST),
+            "EXCEPTS=e".to_string(),
+            format!("EXTBAN={},{}", ban::EXTBAN_PREFIX, ban::EXTBAN_TYPES),
+            "INVEX=I".to_string(),
+            "KNOCK".to_string(),
+            format!("MAXLIST=b:{0},e:{0},I:{0}", limits.max_list_entries),
+            format!("MODES={}", mode::MAX_PARAM_MODES),
+            format!("NETWORK={}", self.get_network()),
+            format!("NICKLEN={}", limits.nick_len),
+            chan::prefix_token(),
+            "SAFELIST".to_string(),
+            "TARGMAX=JOIN:,KICK:,LIST:,NAMES:,NOTICE:,PART:,PRIVMSG:,WHOIS:".to_string(),
+            format!("TOPICLEN={}", limits.topic_len),
+            "WHOX".to_string(),
+        ]
+    }
+
+    /* as many 005 lines as it takes, no more than 13 tokens apiece so
+     * the nick and the trailing text still fit in MAX_MSG_PARAMS, and
+     * each short enough to fit in 512 bytes */
+    pub fn isupport_replies(&self, nick: &str) -> ClientReplies {
+        let overhead = format!(":{} 005 {}  :are supported by this server", self.get_host(), nick).len() + 2;
+        let room = rfc::MAX_MSG_SIZE.saturating_sub(overhead);
+        let max_tokens = rfc::MAX_MSG_PARAMS - 2;
+        let mut replies = Vec::new();
+        let mut line: Vec<String> = Vec::new();
+        let mut line_len = 0;
+        for token in self.get_isupport() {
+            if !line.is_empty() && (line.len() == max_tokens || line_len + 1 + token.len() > room) {
+                replies.push(Ok(ircReply::ISupport(std::mem::take(&mut line))));
+                line_len = 0;
+            }
+            line_len += if line.is_empty() { token.len() } else { token.len() + 1 };
+            line.push(token);
+        }
+        if !line.is_empty() {
+            replies.push(Ok(ircReply::ISupport(line)));
+        }
+        replies
+    }
+
+    /* every registered user still around, upgraded outside the lock for
+     * the same reason as get_n_clients_in_class() */
+    pub fn list_users_ptr(&self) -> Vec<Arc<User>> {
+        let weak_users = self.namespace
+            .lock()
+            .unwrap()
+            .values()
+            .filter_map(|ent| match ent {
+                NamedEntity::User(user) => Some(Weak::clone(user)),
+                NamedEntity::Chan(_) => None,
+            }).collect::<Vec<_>>();
+        weak_users.iter().filter_map(Weak::upgrade).collect()
+    }
+
+    /* the high water mark for 265/266, bumped as people register */
+    fn note_user_count(&self) {
+        let n_users = self.namespace
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|ent| matches!(ent, NamedEntity::User(_)))
+            .count();
+        let mut max_users = self.max_users.lock().unwrap();
+        *max_users = (*max_users).max(n_users);
+    }
+
+    /* RPL_LUSERCLIENT through RPL_GLOBALUSERS - with no server links
+     * local and global are one and the same, and the ones that would
+     * only say zero are left out like everyone else does */
+    pub fn lusers_replies(&self) -> ClientReplies {
+        let users = self.list_users_ptr();
+        let n_users = users.len();
+        let invisible = users.iter().filter(|user| user.is_invisible()).count();
+        let opers = users.iter().filter(|user| user.is_oper()).count();
+        let unknown = self.get_n_clients().saturating_sub(n_users);
+        let n_chans = self.list_chans_ptr().len();
+        let max_users = (*self.max_users.lock().unwrap()).max(n_users);
+        let mut replies = vec![Ok(ircReply::LuserClient(n_users - invisible, invisible))];
+        if opers > 0 {
+            replies.push(Ok(ircReply::LuserOp(opers)));
+        }
+        if unknown > 0 {
+            replies.push(Ok(ircReply::LuserUnknown(unknown)));
+        }
+        if n_chans > 0 {
+            replies.push(Ok(ircReply::LuserChannels(n_chans)));
+        }
+        replies.push(Ok(ircReply::LuserMe(n_users)));
+        replies.push(Ok(ircReply::LocalUsers(n_users, max_users)));
+        replies.push(Ok(ircReply::GlobalUsers(n_users, max_users)));
+        replies
+    }
+
+    /* +r safe channels that have run out of ops get one handed back */
+    pub async fn reop_chans(&self) {
+        for chan in self.list_chans_ptr() {
+            if let Some(nick) = chan.reop() {
+                chan.broadcast(&format!(":{} MODE {} +o {}", self.get_host(), chan.get_name(), nick)).await;
+            }
+        }
+    }
+
+    /* take timed bans off the lists once they're up, and tell the
+     * channel, a server-side MODE like any other */
+    pub async fn expire_masks(&self) {
+        let now = Utc::now().timestamp();
+        for chan in self.list_chans_ptr() {
+            let expired = chan.take_expired_masks(now)
+                .into_iter()
+                .map(|(mode, mask)| ModeChange { set: false, mode, param: Some(mask) })
+                .collect::<Vec<_>>();
+            for changes in expired.chunks(mode::MAX_PARAM_MODES) {
+                let line = format!(":{} MODE {} {}", self.get_host(), chan.get_name(), mode::format_changes(changes));
+                chan.broadcast(&line).await;
+            }
+        }
     }
 
     pub fn get_umodes(&self) -> String {
@@ -442,25 +1083,96 @@ impl Core {
         Ok(ircReply::None)
     }
 
-    pub async fn join_chan(self: &Arc<Core>, chanmask: &str, user: &Arc<User>) -> Result<ClientReplies, GenError> {
-        let mut replies = Vec::new();
-        if !rfc::valid_channel(chanmask) {
-            replies.push(Err(ircError::NoSuchChannel(chanmask.to_string())));
-            return Ok(replies);
+    /* every safe channel going by this short name */
+    pub fn find_safe_chans(&self, short_name: &str) -> Vec<Arc<Channel>> {
+        self.list_chans_ptr()
+            .into_iter()
+            .filter(|chan| chan.get_short_name().is_some_and(|name| self.casefold(name) == self.casefold(short_name)))
+            .collect()
+    }
+
+    /* safe channels go by a generated name: JOIN !!name makes a new one
+     * (Ok(name, true)), and JOIN !name finds one that already exists by
+     * its short name - everything else is taken as it comes */
+    fn resolve_join_name(&self, chanmask: &str) -> Result<(String, bool), ircError> {
+        let chan_len = self.get_limits().chan_len;
+        if let Some(short_name) = chanmask.strip_prefix("!!") {
+            let name = format!("!{}{}", rfc::safe_channel_id(Utc::now().timestamp()), short_name);
+            if !rfc::valid_channel(&name) || name.len() > chan_len {
+                return Err(ircError::NoSuchChannel(chanmask.to_string()));
+            }
+            if !self.find_safe_chans(short_name).is_empty() {
+                return Err(ircError::TooManyTargets(chanmask.to_string()));
+            }
+            return Ok((name, true));
+        }
+        if let Some(short_name) = chanmask.strip_prefix('!') {
+            if self.get_chan(chanmask).is_ok() {
+                return Ok((chanmask.to_string(), false));
+            }
+            let mut found = self.find_safe_chans(short_name);
+            return match found.len() {
+                0 => Err(ircError::NoSuchChannel(chanmask.to_string())),
+                1 => Ok((found.remove(0).get_name(), false)),
+                _ => Err(ircError::TooManyTargets(chanmask.to_string())),
+            };
         }
+        /* a channel made before a REHASH shortened chan_len can still be joined */
+        if self.get_chan(chanmask).is_ok() || (rfc::valid_channel(chanmask) && chanmask.len() <= chan_len) {
+            Ok((chanmask.to_string(), false))
+        } else {
+            Err(ircError::NoSuchChannel(chanmask.to_string()))
+        }
+    }
+
+    pub async fn join_chan(self: &Arc<Core>, chanmask: &str, key: Option<&str>, user: &Arc<User>) -> Result<ClientReplies, GenError> {
+        let mut replies = Vec::new();
+        let (chanmask, new_safe_chan) = match self.resolve_join_name(chanmask) {
+            Ok(resolved) => resolved,
+            Err(err) => {
+                replies.push(Err(err));
+                return Ok(replies);
+            }
+        };
+        let chanmask = &chanmask[..];
         let nick = user.get_nick();
+        let max_channels = self.get_limits().max_channels;
         match self.get_chan(chanmask) {
             Ok(chan) => {
                 /* need to check if user is already in chan */
                 if chan.is_joined(&nick) {
                     return Ok(replies);
                 }
-                chan.add_user(user, ChanFlags::None).await
+                if user.get_n_channels() >= max_channels {
+                    replies.push(Err(ircError::TooManyChannels(chanmask.to_string())));
+                    return Ok(replies);
+                }
+                if let Err(err) = chan.check_join(user, key) {
+                    replies.push(Err(err));
+                    return Ok(replies);
+                }
+                chan.add_user(user, &[]).await
+            },
+            Err(_) if user.get_n_channels() >= max_channels => {
+                replies.push(Err(ircError::TooManyChannels(chanmask.to_string())));
+                Ok(replies)
+            },
+            /* only JOIN !!name gets to make a safe channel */
+            Err(_) if chanmask.starts_with('!') && !new_safe_chan => {
+                replies.push(Err(ircError::NoSuchChannel(chanmask.to_string())));
+                Ok(replies)
             },
             Err(_) => {
-                let chan = Arc::new(Channel::new(&self, chanmask));
+                let chan = Arc::new(Channel::new(self, chanmask));
                 self.insert_name(chanmask, NamedEntity::Chan(Arc::clone(&chan)))?; // what happens if this error does occur?
-                chan.add_user(user, ChanFlags::Op).await
+                /* whoever makes the channel owns it - on a safe channel
+                 * that's the RFC's creator status - but nobody gets to
+                 * run a +channel */
+                if chan.is_modeless() {
+                    chan.add_user(user, &[]).await
+                } else {
+                    chan.add_user(user, &[ChanFlags::Owner, ChanFlags::Op]).await
+                }
             }
         }
     }
@@ -468,28 +1180,33 @@ impl Core {
     /* don't want anyone to take our nick while we're in the middle of faffing around... */
     pub fn try_nick_change(&self, user: &User, new_nick: &str) -> Result<ircReply, GenError> {
         let mut big_fat_mutex_lock = self.namespace.lock().unwrap();
-        let mut chanlist_mutex_lock = user.channel_list.lock().unwrap();
+        /* a copy, so the channels can be locked without holding our list */
+        let channels = user.channel_list.lock().unwrap().clone();
         let nick = new_nick.to_string();
         let old_nick = user.get_nick();
-        if big_fat_mutex_lock.contains_key(&nick) {
+        let key = self.casefold(&nick);
+        let old_key = self.casefold(&old_nick);
+        /* going from foo to Foo is fine, the key stays the same */
+        if key != old_key && big_fat_mutex_lock.contains_key(&key) {
             gef!(ircError::NicknameInUse(nick))
         } else {
-            if let Some(val) = big_fat_mutex_lock.remove(&old_nick) {
+            if let Some(val) = big_fat_mutex_lock.remove(&old_key) {
                 /* move to new key */
-                big_fat_mutex_lock.insert(nick.clone(), val);
+                big_fat_mutex_lock.insert(key, val);
+                self.record_whowas(user);
 
                 /* update User struct */
                 *user.nick.lock().unwrap() = nick;
 
                 /* update channels list */
-                for (chan_name, chan_wptr) in chanlist_mutex_lock.clone().iter() {
-                    if let Some(chan) = Weak::upgrade(&chan_wptr) {
-                        if let Err(err) = chan.update_nick(&old_nick, &new_nick) {
+                for (chan_name, chan_wptr) in channels.iter() {
+                    if let Some(chan) = Weak::upgrade(chan_wptr) {
+                        if let Err(err) = chan.update_nick(&old_nick, new_nick) {
                             warn!("try to update nick {} in chan {} despite not being in chan, error: {}", &chan_name, &old_nick, err);
                         }
                     } else {
                         debug!("try_nick_change(): can't upgrade pointer to {}, deleting key", chan_name);
-                        chanlist_mutex_lock.remove(chan_name);
+                        user.channel_list.lock().unwrap().remove(chan_name);
                     }
                 }
             }
@@ -520,10 +1237,13 @@ impl Core {
             username,
             real_name,
             host.clone(),
+            client.get_ip(),
+            client.is_tls(),
             server,
             client,
         );
         self.insert_name(&nick, NamedEntity::User(Arc::downgrade(&user)))?;
+        self.note_user_count();
         Ok(user)
     }
 
@@ -533,7 +1253,7 @@ impl Core {
         let mut chan_strings = Vec::new();
         for value in self.namespace.lock().unwrap().values() {
             if let NamedEntity::Chan(chan_ptr) = value {
-                channels.push(Arc::clone(&chan_ptr));
+                channels.push(Arc::clone(chan_ptr));
             }
         }
 
@@ -541,7 +1261,7 @@ impl Core {
             if channel.is_joined(nick) {
                 chan_strings.push(channel.get_name());
                 if purge {
-                    channel.rm_key(&nick);
+                    channel.rm_key(nick);
                     if channel.is_empty() && self.remove_name(&channel.get_name()).is_ok() {
                         debug!("_search_user_chans(): remove channel {} from IRC HashMap", &channel.get_name());
                     }
@@ -573,28 +1293,258 @@ pub async fn command(irc: &Arc<Core>, client: &Arc<Client>, params: ParsedMsg) -
 
     match &cmd[..] {
         "NICK" => nick(irc, client, params).await,
+        "QUIT" => quit(params),
+        "PING" => ping(irc, client, params).await,
+        "PONG" => Ok(Vec::new()), /* process_lines() already counted it as activity */
         "USER" => user(irc, client, params).await,
+        "CAP" => cap(irc, client, params).await,
         "PRIVMSG" if registered => msg(irc, &client.get_user(), params, false).await,
         "NOTICE" if registered => msg(irc, &client.get_user(), params, true).await,
         "JOIN" if registered => join(irc, &client.get_user(), params).await,
         "PART" if registered => part(irc, &client.get_user(), params).await,
+        "KICK" if registered => kick(irc, &client.get_user(), params).await,
+        "INVITE" if registered => invite(irc, &client.get_user(), params).await,
+        "KNOCK" if registered => knock(irc, &client.get_user(), params).await,
         "TOPIC" if registered => topic(irc, &client.get_user(), params).await,
-        "LIST" if registered => list(irc).await,
-        "PART" | "JOIN" | "PRIVMSG" | "NOTICE" | "TOPIC" | "LIST" if !registered => gef!(ircError::NotRegistered),
+        "LIST" if registered => list(irc, client, &client.get_user(), params).await,
+        "NAMES" if registered => names(irc, &client.get_user(), params).await,
+        "LUSERS" if registered => lusers(irc),
+        "MOTD" if registered => motd(irc, client, &client.get_user()),
+        "VERSION" if registered => version(irc, &client.get_user()),
+        "TIME" if registered => time(irc),
+        "ADMIN" if registered => admin(irc),
+        "INFO" if registered => info(irc),
+        "STATS" if registered => stats(irc, client, &client.get_user(), params),
+        "WHOIS" if registered => whois(irc, &client.get_user(), params),
+        "WHO" if registered => who(irc, &client.get_user(), params),
+        "WHOWAS" if registered => whowas(irc, params),
+        "MODE" if registered => mode(irc, &client.get_user(), params).await,
+        "LOGIN" if registered => login(irc, &client.get_user(), params).await,
+        "OPER" if registered => oper(irc, &client.get_user(), params).await,
+        "REHASH" if registered => rehash(irc, &client.get_user()).await,
+        "DIE" if registered => die(irc, &client.get_user(), params).await,
+        "PART" | "KICK" | "INVITE" | "KNOCK" | "JOIN" | "PRIVMSG" | "NOTICE" | "TOPIC" | "LIST" | "NAMES" | "LUSERS" | "MOTD" | "VERSION" | "TIME" | "ADMIN" | "INFO" | "STATS" | "WHOIS" | "WHO" | "WHOWAS" | "MODE" | "LOGIN" | "OPER" | "REHASH" | "DIE" if !registered => gef!(ircError::NotRegistered),
         _ => gef!(ircError::UnknownCommand(params.command.to_string())),
     }
 }
 
-pub async fn list(irc: &Core) -> Result<ClientReplies, GenError> {
-    let tuple_vector = irc.get_list_reply();
+/* hand the reason back up to process_lines(), which lets the client
+ * handler do the actual disconnecting */
+pub fn quit(params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    match params.opt_params.first() {
+        Some(msg) if !msg.is_empty() => Err(GenError::Quit(format!("Quit: {}", msg))),
+        _ => Err(GenError::Quit("Client Quit".to_string())),
+    }
+}
+
+pub async fn mode(irc: &Core, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if params.opt_params.is_empty() {
+        replies.push(Err(ircError::NeedMoreParams("MODE".to_string())));
+        return Ok(replies);
+    }
+
+    let target = params.opt_params.remove(0);
+    match irc.get_name(&target) {
+        Some(NamedEntity::Chan(chan)) => chan_mode(&chan, user, params.opt_params).await,
+        Some(NamedEntity::User(_user)) => user_mode(user, &target, params.opt_params).await,
+        None if rfc::valid_channel(&target) => {
+            replies.push(Err(ircError::NoSuchChannel(target)));
+            Ok(replies)
+        },
+        None => {
+            replies.push(Err(ircError::NoSuchNick(target)));
+            Ok(replies)
+        },
+    }
+}
+
+async fn chan_mode(chan: &Channel, user: &User, mut params: Vec<String>) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
-    for (chan, topic) in tuple_vector.iter() {
-        replies.push(Ok(ircReply::ListReply(chan.get_name(), chan.get_n_users(), topic.clone())));
+    let name = chan.get_name();
+    if params.is_empty() {
+        let show_key = chan.is_joined(&user.get_nick());
+        replies.push(Ok(ircReply::ChannelModeIs(name.clone(), chan.get_mode_string(show_key))));
+        replies.push(Ok(ircReply::CreationTime(name, chan.get_created())));
+        return Ok(replies);
+    }
+
+    if chan.is_modeless() {
+        replies.push(Err(ircError::NoChanModes(name)));
+        return Ok(replies);
+    }
+
+    let modestring = params.remove(0);
+    let (changes, mut errors) = mode::parse_chan_modes(&name, &modestring, &params);
+    replies.append(&mut errors);
+    let (applied, mut errors) = mode::apply_chan_modes(chan, user, changes);
+    replies.append(&mut errors);
+    if !applied.is_empty() {
+        chan.notify_mode(user, &mode::format_changes(&applied)).await;
+    }
+    Ok(replies)
+}
+
+/* users can only look at and change their own modes - +i comes and goes
+ * as they like, o can be dropped but only OPER hands it out */
+async fn user_mode(user: &Arc<User>, target: &str, params: Vec<String>) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if user.irc.casefold(target) != user.irc.casefold(&user.get_nick()) {
+        replies.push(Err(ircError::UsersDontMatch));
+        return Ok(replies);
+    }
+    let modestring = match params.first() {
+        Some(modestring) => modestring,
+        None => {
+            replies.push(Ok(ircReply::UModeIs(user.get_mode_string())));
+            return Ok(replies);
+        }
+    };
+
+    let mut applied = Vec::new();
+    let mut set = true;
+    let mut unknown = false;
+    for mode in modestring.chars() {
+        match mode {
+            '+' => set = true,
+            '-' => set = false,
+            'i' if user.is_invisible() != set => {
+                user.set_invisible(set);
+                applied.push(ModeChange { set, mode, param: None });
+            },
+            'o' if !set && user.is_oper() => {
+                user.set_oper(false);
+                applied.push(ModeChange { set, mode, param: None });
+            },
+            'i' | 'o' => (),
+            _ => unknown = true,
+        }
+    }
+    if unknown {
+        replies.push(Err(ircError::UModeUnknownFlag));
+    }
+    if !applied.is_empty() {
+        let nick = user.get_nick();
+        user.send_line(&format!(":{} MODE {} :{}", nick, nick, mode::format_changes(&applied))).await?;
     }
-    replies.push(Ok(ircReply::EndofList));
     Ok(replies)
 }
 
+/* allowed before registration too, some clients PING while they wait */
+pub async fn ping(irc: &Core, client: &Client, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    match params.opt_params.first() {
+        Some(token) if !token.is_empty() => {
+            let host = irc.get_host();
+            client.send_line(&format!(":{} PONG {} :{}", host, host, token)).await?;
+        },
+        _ => replies.push(Err(ircError::NoOrigin)),
+    }
+    Ok(replies)
+}
+
+/* LOGIN <account> <password> against the [[account]] blocks */
+pub async fn login(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if params.opt_params.len() < 2 {
+        replies.push(Err(ircError::NeedMoreParams("LOGIN".to_string())));
+        return Ok(replies);
+    }
+
+    match irc.check_account(&params.opt_params[0], &params.opt_params[1]) {
+        Some(account) => {
+            info!("{} logged in as {}", user.get_prefix(), account);
+            user.set_account(Some(account.clone()));
+            replies.push(Ok(ircReply::LoggedIn(user.get_prefix(), account)));
+        },
+        None => {
+            warn!("failed LOGIN attempt by {}", user.get_prefix());
+            replies.push(Err(ircError::SaslFail));
+        },
+    }
+    Ok(replies)
+}
+
+pub async fn oper(irc: &Core, user: &Arc<User>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if params.opt_params.len() < 2 {
+        replies.push(Err(ircError::NeedMoreParams("OPER".to_string())));
+        return Ok(replies);
+    }
+
+    if irc.check_oper(&params.opt_params[0], &params.opt_params[1]) {
+        info!("{} is now an oper (oper block {})", user.get_prefix(), &params.opt_params[0]);
+        user.set_oper(true);
+        user.send_line(&format!(":{} MODE {} :+o", user.get_nick(), user.get_nick())).await?;
+        replies.push(Ok(ircReply::YoureOper));
+    } else {
+        warn!("failed OPER attempt by {}", user.get_prefix());
+        replies.push(Err(ircError::PasswdMismatch));
+    }
+    Ok(replies)
+}
+
+pub async fn rehash(irc: &Arc<Core>, user: &User) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if !user.is_oper() {
+        replies.push(Err(ircError::NoPrivileges));
+        return Ok(replies);
+    }
+
+    info!("REHASH requested by {}", user.get_prefix());
+    replies.push(Ok(ircReply::Rehashing(irc.get_config_path())));
+    let report = irc.rehash().await;
+    report.log();
+    for line in report.changed {
+        replies.push(Ok(ircReply::Notice(format!("*** REHASH: {}", line))));
+    }
+    for line in report.failed {
+        replies.push(Ok(ircReply::Notice(format!("*** REHASH failed: {}", line))));
+    }
+    Ok(replies)
+}
+
+pub async fn die(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if !user.is_oper() {
+        replies.push(Err(ircError::NoPrivileges));
+        return Ok(replies);
+    }
+
+    let reason = if params.opt_params.is_empty() {
+        irc.get_shutdown_config().reason
+    } else {
+        params.opt_params.join(" ")
+    };
+    info!("DIE requested by {}", user.get_prefix());
+    irc.shutdown(&reason);
+    Ok(replies)
+}
+
+/* LIST [masks and ELIST conditions] - which channels make it is worked
+ * out here, sending them is left to Client::send_list() */
+pub async fn list(irc: &Core, client: &Arc<Client>, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let param = params.opt_params.first().map(String::as_str).unwrap_or_default();
+    let filter = ListFilter::parse(param, Utc::now().timestamp());
+    let chans = match filter.exact_names() {
+        Some(names) => names.iter().filter_map(|name| irc.get_chan(name).ok()).collect(),
+        None => irc.list_chans_ptr(),
+    };
+    let mut replies = chans.iter()
+        .filter(|chan| chan.is_visible_to(user))
+        .filter_map(|chan| {
+            let topic = chan.get_topic();
+            if filter.matches(chan, &topic) {
+                Some(ircReply::ListReply(chan.get_name(), chan.get_n_users(), topic))
+            } else {
+                None
+            }
+        }).collect::<Vec<_>>();
+    replies.push(ircReply::EndofList);
+    client.send_list(replies);
+    Ok(Vec::new())
+}
+
 pub async fn topic(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
     if params.opt_params.is_empty() {
@@ -602,16 +1552,23 @@ pub async fn topic(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<Cli
         return Ok(replies);
     }
 
-    /* are ya in the chan? */
     let chanmask = params.opt_params.remove(0);
-    let chan = irc.get_chan(&chanmask)?;
-    if !chan.is_joined(&user.get_nick()) {
-        replies.push(Err(ircError::NotOnChannel(chanmask)));
-        return Ok(replies);
-    }
+    let chan = match irc.get_chan(&chanmask) {
+        Ok(chan) => chan,
+        Err(err) => {
+            replies.push(Err(err));
+            return Ok(replies);
+        }
+    };
+    let chanmask = chan.get_name();
+    let joined = chan.is_joined(&user.get_nick());
 
-    /* just want to receive topic? */
+    /* just want to receive topic? outsiders can, unless it's +s */
     if params.opt_params.is_empty() {
+        if !joined && chan.has_mode('s') {
+            replies.push(Err(ircError::NotOnChannel(chanmask)));
+            return Ok(replies);
+        }
         if let Some(topic) = chan.get_topic() {
             replies.push(Ok(ircReply::Topic(chanmask.clone(), topic.text)));
             replies.push(Ok(ircReply::TopicSetBy(chanmask, topic.usermask, topic.timestamp)));
@@ -621,9 +1578,15 @@ pub async fn topic(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<Cli
         return Ok(replies);
     };
     
-    /* set topic IF permissions allow */
-    if chan.is_op(user) {
-        chan.set_topic(&params.opt_params.remove(0), &user);
+    /* set topic IF permissions allow, anyone on the channel may unless
+     * it's +t, and an empty one clears it */
+    if !joined {
+        replies.push(Err(ircError::NotOnChannel(chanmask)));
+    } else if !chan.has_mode('t') || chan.is_halfop(user) {
+        let text = params.opt_params.remove(0);
+        let text = rfc::truncate(&text, irc.get_limits().topic_len);
+        chan.set_topic(text, user);
+        chan.notify_topic(user, text).await;
     } else {
         replies.push(Err(ircError::ChanOPrivsNeeded(chanmask)));
     }
@@ -639,10 +1602,24 @@ pub async fn join(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> R
 
     /* JOIN can take a second argument. The format is:
      * JOIN comma,sep.,chan,list comma,sep.,key,list
-     * but I'll leave key implementation til later */
+     * keys pair up with channels in order, and there may be fewer
+     * keys than channels. JOIN 0 is a special case, leave everything */
     let targets = params.opt_params.remove(0);
+    if targets == "0" {
+        for chan in user.get_channel_list().iter().filter_map(Weak::upgrade) {
+            replies.push(irc.part_chan(&chan.get_name(), user, "").await);
+        }
+        return Ok(replies);
+    }
+    let keys = if params.opt_params.is_empty() {
+        String::new()
+    } else {
+        params.opt_params.remove(0)
+    };
+    let mut keys = keys.split(',');
     for target in targets.split(',') {
-        replies.append(&mut irc.join_chan(&target, user).await?);
+        let key = keys.next().filter(|key| !key.is_empty());
+        replies.append(&mut irc.join_chan(target, key, user).await?);
     }
     Ok(replies)
 }
@@ -661,8 +1638,161 @@ pub async fn part(irc: &Arc<Core>, user: &Arc<User>, mut params: ParsedMsg) -> R
         params.opt_params.remove(0)
     };
     for target in targets.split(',') {
-        replies.push(irc.part_chan(&target, user, &part_msg).await);
+        replies.push(irc.part_chan(target, user, &part_msg).await);
+    }
+    Ok(replies)
+}
+
+/* KICK #chan nick1,nick2 :reason - halfops and up may kick, but not
+ * anyone who outranks them */
+pub async fn kick(irc: &Core, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if params.opt_params.len() < 2 {
+        replies.push(Err(ircError::NeedMoreParams("KICK".to_string())));
+        return Ok(replies);
+    }
+
+    let chanmask = params.opt_params.remove(0);
+    let targets = params.opt_params.remove(0);
+    let chan = match irc.get_chan(&chanmask) {
+        Ok(chan) => chan,
+        Err(err) => {
+            replies.push(Err(err));
+            return Ok(replies);
+        }
+    };
+    let nick = user.get_nick();
+    if !chan.is_joined(&nick) {
+        replies.push(Err(ircError::NotOnChannel(chanmask)));
+        return Ok(replies);
+    }
+    let rank = chan.get_rank(&nick);
+    if rank < Some(ChanFlags::HalfOp) {
+        replies.push(Err(ircError::ChanOPrivsNeeded(chanmask)));
+        return Ok(replies);
+    }
+    let reason = params.opt_params.first().filter(|reason| !reason.is_empty());
+
+    for target in targets.split(',').filter(|target| !target.is_empty()) {
+        let victim = match chan.get_member(target) {
+            Some(victim) => victim,
+            None => {
+                replies.push(Err(ircError::UserNotInChannel(target.to_string(), chanmask.clone())));
+                continue;
+            }
+        };
+        if chan.get_rank(target) > rank {
+            replies.push(Err(ircError::ChanOPrivsNeeded(chanmask.clone())));
+            continue;
+        }
+        if let Err(err) = chan.kick_user(user, &victim, reason.map(String::as_str)).await {
+            warn!("{}", err);
+        }
+    }
+    Ok(replies)
+}
+
+/* INVITE nick #chan - any member may invite to an ordinary channel,
+ * only halfops and up to a +i one */
+pub async fn invite(irc: &Core, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if params.opt_params.len() < 2 {
+        replies.push(Err(ircError::NeedMoreParams("INVITE".to_string())));
+        return Ok(replies);
+    }
+
+    let target = params.opt_params.remove(0);
+    let chanmask = params.opt_params.remove(0);
+    let target_user = match irc.get_name(&target) {
+        Some(NamedEntity::User(ptr)) => match Weak::upgrade(&ptr) {
+            Some(target_user) => target_user,
+            None => {
+                replies.push(Err(ircError::NoSuchNick(target)));
+                return Ok(replies);
+            }
+        },
+        _ => {
+            replies.push(Err(ircError::NoSuchNick(target)));
+            return Ok(replies);
+        }
+    };
+    let chan = match irc.get_chan(&chanmask) {
+        Ok(chan) => chan,
+        Err(err) => {
+            replies.push(Err(err));
+            return Ok(replies);
+        }
+    };
+    if !chan.is_joined(&user.get_nick()) {
+        replies.push(Err(ircError::NotOnChannel(chanmask)));
+        return Ok(replies);
+    }
+    if chan.has_mode('i') && !chan.is_halfop(user) {
+        replies.push(Err(ircError::ChanOPrivsNeeded(chanmask)));
+        return Ok(replies);
+    }
+    let target = target_user.get_nick();
+    let chanmask = chan.get_name();
+    if chan.is_joined(&target) {
+        replies.push(Err(ircError::UserOnChannel(target, chanmask)));
+        return Ok(replies);
+    }
+
+    chan.invite(&target_user);
+    target_user.send_line(&format!(":{} INVITE {} :{}", user.get_prefix(), target, chanmask)).await?;
+    chan.notice_ops(user, &format!("{} invited {} into the channel", user.get_nick(), target)).await;
+    replies.push(Ok(ircReply::Inviting(target, chanmask)));
+    Ok(replies)
+}
+
+/* KNOCK #chan [:message] - ask the ops of a +i channel to let you in,
+ * no more often than limits.knock_delay allows for you or for them */
+pub async fn knock(irc: &Core, user: &Arc<User>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if params.opt_params.is_empty() {
+        replies.push(Err(ircError::NeedMoreParams("KNOCK".to_string())));
+        return Ok(replies);
+    }
+
+    let chanmask = params.opt_params.remove(0);
+    let chan = match irc.get_chan(&chanmask) {
+        Ok(chan) => chan,
+        Err(err) => {
+            replies.push(Err(err));
+            return Ok(replies);
+        }
+    };
+    let chanmask = chan.get_name();
+    if chan.is_joined(&user.get_nick()) {
+        replies.push(Err(ircError::KnockOnChan(chanmask)));
+        return Ok(replies);
     }
+    if !chan.has_mode('i') {
+        replies.push(Err(ircError::ChanOpen(chanmask)));
+        return Ok(replies);
+    }
+    if chan.is_banned(user) {
+        replies.push(Err(ircError::BannedFromChan(chanmask)));
+        return Ok(replies);
+    }
+    let now = Utc::now().timestamp();
+    let delay = irc.get_limits().knock_delay as i64;
+    if !user.may_knock(now, delay) {
+        replies.push(Err(ircError::TooManyKnock(chanmask, "user".to_string())));
+        return Ok(replies);
+    }
+    if !chan.take_knock(now, delay) {
+        replies.push(Err(ircError::TooManyKnock(chanmask, "channel".to_string())));
+        return Ok(replies);
+    }
+
+    let text = match params.opt_params.first() {
+        Some(msg) if !msg.is_empty() => format!("[Knock] by {} ({})", user.get_prefix(), msg),
+        _ => format!("[Knock] by {} (no reason given)", user.get_prefix()),
+    };
+    chan.notice_ops(user, &text).await;
+    user.note_knock(now);
+    replies.push(Ok(ircReply::KnockDelivered(chanmask)));
     Ok(replies)
 }
 
@@ -695,6 +1825,7 @@ pub async fn msg(
     // if there are more than two arguments,
     // concatenate the remainder to one string
     let message = params.opt_params.join(" ");
+    send_u.mark_active();
     trace!("{} from user {} to {}, content: {}", cmd, send_u.get_nick(), targets, message);
 
     // loop over targets
@@ -703,7 +1834,7 @@ pub async fn msg(
             Some(NamedEntity::User(user_weak)) => {
                 match User::upgrade(&user_weak, target) {
                     Ok(recv_u) => {
-                        replies.push(recv_u.send_msg(&send_u, &cmd, &target, &message).await?);
+                        replies.push(recv_u.send_msg(send_u, cmd, target, &message).await?);
                     },
                     Err(GenError::DeadUser(nick)) => {
                         let _res = irc.search_user_chans_purge(&nick);
@@ -716,7 +1847,7 @@ pub async fn msg(
                 }
             },
             Some(NamedEntity::Chan(chan))
-                => replies.push(chan.send_msg(&send_u, &cmd, &target, &message).await?),
+                => replies.push(chan.send_msg(send_u, cmd, target, &message).await?),
             None => replies.push(Err(ircError::NoSuchNick(target.to_string())))
         }
     }
@@ -753,24 +1884,24 @@ pub async fn user(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
         }
         ClientType::ProtoUser(proto_user_ref) => {
             // got nick already? if so, complete registration
-            let proto_user = proto_user_ref.lock().unwrap();
-            if let Some(nick) = &proto_user.nick {
-                // had nick already, complete registration
-                let ret = Some(ClientType::User(
-                    irc.register(client, nick.clone(), username.clone(), real_name)?, // propagate the error if it goes wrong
-                ));
-                replies.push(Ok(ircReply::Welcome(nick.clone(), username.clone(), client.get_host_string())));
-                replies.push(Ok(ircReply::YourHost(irc.get_host(), irc.get_version())));
-                replies.push(Ok(ircReply::Created(irc.get_date())));
-                replies.push(Ok(ircReply::MyInfo(irc.get_host(), irc.get_version(), irc.get_umodes(), irc.get_chanmodes())));
-                ret
-            } else {
-                // don't see an error in the irc file,
-                // except the one if you're already reg'd
-                // NOTICE_BLOCKY
-                proto_user_ref.lock().unwrap().username = Some(username);
-                proto_user_ref.lock().unwrap().real_name = Some(real_name);
-                None
+            let mut proto_user = proto_user_ref.lock().unwrap();
+            match &proto_user.nick {
+                // had nick already, complete registration - unless CAP
+                // negotiation is still going on, then CAP END does it
+                Some(nick) if !client.is_cap_negotiating() => {
+                    let ret = Some(ClientType::User(
+                        irc.register(client, nick.clone(), username.clone(), real_name)?, // propagate the error if it goes wrong
+                    ));
+                    replies.append(&mut welcome_burst(irc, client, nick, &username));
+                    ret
+                },
+                _ => {
+                    // don't see an error in the irc file,
+                    // except the one if you're already reg'd
+                    proto_user.username = Some(username);
+                    proto_user.real_name = Some(real_name);
+                    None
+                }
             }
         } //ClientType::Server(_server_ref) => (None, None, false)
     };
@@ -781,10 +1912,351 @@ pub async fn user(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
     Ok(replies)
 }
 
+/* CAP LS/LIST/REQ/END, enough for clients to turn on what NAMES offers -
+ * LS or REQ before registering holds it up until END */
+pub async fn cap(irc: &Core, client: &Arc<Client>, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if params.opt_params.is_empty() {
+        replies.push(Err(ircError::NeedMoreParams("CAP".to_string())));
+        return Ok(replies);
+    }
+
+    let subcmd = params.opt_params.remove(0).to_ascii_uppercase();
+    let prefix = format!(":{} CAP {}", irc.get_host(), client.get_nick_or_star());
+    let registered = client.is_registered();
+    match &subcmd[..] {
+        "LS" => {
+            if !registered {
+                client.set_cap_negotiating(true);
+            }
+            client.send_line(&format!("{} LS :{}", prefix, CAPABILITIES.join(" "))).await?;
+        },
+        "LIST" => {
+            client.send_line(&format!("{} LIST :{}", prefix, client.get_caps().join(" "))).await?;
+        },
+        "REQ" => {
+            if !registered {
+                client.set_cap_negotiating(true);
+            }
+            /* all or nothing, if any of them is one we don't know the
+             * whole request is refused */
+            let request = params.opt_params.first().cloned().unwrap_or_default();
+            let wanted = request.split_whitespace()
+                .map(|cap| match cap.strip_prefix('-') {
+                    Some(cap) => (cap, false),
+                    None => (cap, true),
+                })
+                .collect::<Vec<_>>();
+            if wanted.iter().all(|(cap, _enable)| CAPABILITIES.contains(cap)) {
+                for (cap, enable) in wanted.iter() {
+                    client.set_cap(cap, *enable);
+                }
+                client.send_line(&format!("{} ACK :{}", prefix, request)).await?;
+            } else {
+                client.send_line(&format!("{} NAK :{}", prefix, request)).await?;
+            }
+        },
+        "END" => {
+            client.set_cap_negotiating(false);
+            if let ClientType::ProtoUser(proto_user_ref) = client.get_client_type() {
+                let proto_user = proto_user_ref.lock().unwrap();
+                if let (Some(nick), Some(username), Some(real_name)) = (&proto_user.nick, &proto_user.username, &proto_user.real_name) {
+                    let user = irc.register(client, nick.clone(), username.clone(), real_name.clone())?;
+                    replies.append(&mut welcome_burst(irc, client, nick, username));
+                    client.set_client_type(ClientType::User(user));
+                }
+            }
+        },
+        _ => replies.push(Err(ircError::InvalidCapCmd(subcmd))),
+    }
+    Ok(replies)
+}
+
+/* NAMES [#chan,...] - with no channels given, every channel you can see */
+pub async fn names(irc: &Core, user: &User, mut params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mut replies = Vec::new();
+    if params.opt_params.is_empty() {
+        for chan in irc.list_chans_ptr().iter().filter(|chan| chan.is_visible_to(user)) {
+            replies.append(&mut chan.names_replies(user));
+        }
+        if replies.is_empty() {
+            replies.push(Ok(ircReply::EndofNames("*".to_string())));
+        }
+        return Ok(replies);
+    }
+
+    let targets = params.opt_params.remove(0);
+    for target in targets.split(',').filter(|target| !target.is_empty()) {
+        match irc.get_chan(target) {
+            Ok(chan) if chan.is_visible_to(user) => replies.append(&mut chan.names_replies(user)),
+            /* a secret channel looks just like one that isn't there */
+            _ => replies.push(Ok(ircReply::EndofNames(target.to_string()))),
+        }
+    }
+    Ok(replies)
+}
+
+/* everything a client gets told once NICK and USER are both in */
+fn welcome_burst(irc: &Core, client: &Client, nick: &str, username: &str) -> ClientReplies {
+    let mut replies = vec![
+        Ok(ircReply::Welcome(irc.get_network(), nick.to_string(), username.to_string(), client.get_host_string())),
+        Ok(ircReply::YourHost(irc.get_host(), irc.get_version())),
+        Ok(ircReply::Created(irc.get_date())),
+        Ok(ircReply::MyInfo(irc.get_host(), irc.get_version(), irc.get_umodes(), irc.get_chanmodes())),
+    ];
+    replies.append(&mut irc.isupport_replies(nick));
+    replies.append(&mut irc.lusers_replies());
+    replies.append(&mut motd_replies(irc, client, nick));
+    replies
+}
+
+/* the MOTD for whichever listener the client came in on, with the
+ * placeholders filled in and long lines wrapped to fit 372s */
+fn motd_replies(irc: &Core, client: &Client, nick: &str) -> ClientReplies {
+    let motd = match irc.get_motd(&client.get_listener()) {
+        Some(motd) => motd,
+        None => return vec![Err(ircError::NoMotd)],
+    };
+    let network = irc.get_network();
+    let overhead = format!(":{} 372 {} :- ", irc.get_host(), nick).len() + 2;
+    let room = rfc::MAX_MSG_SIZE.saturating_sub(overhead);
+    let mut replies = vec![Ok(ircReply::MotdStart(irc.get_host()))];
+    for line in motd.iter() {
+        let line = line.replace("${nick}", nick).replace("${network}", &network);
+        for piece in rfc::wrap(&line, room) {
+            replies.push(Ok(ircReply::Motd(piece.to_string())));
+        }
+    }
+    replies.push(Ok(ircReply::EndofMotd));
+    replies
+}
+
+pub fn lusers(irc: &Core) -> Result<ClientReplies, GenError> {
+    Ok(irc.lusers_replies())
+}
+
+pub fn motd(irc: &Core, client: &Client, user: &User) -> Result<ClientReplies, GenError> {
+    Ok(motd_replies(irc, client, &user.get_nick()))
+}
+
+/* WHOIS [server] nick[,nick...] - the server's ignored, there's only us */
+pub fn whois(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let targets = match params.opt_params.len() {
+        0 => return gef!(ircError::NoNickNameGiven),
+        1 => &params.opt_params[0],
+        _ => &params.opt_params[1],
+    };
+    let mut replies = Vec::new();
+    for nick in targets.split(',').filter(|nick| !nick.is_empty()) {
+        match irc.get_nick(nick).as_ref().and_then(Weak::upgrade) {
+            Some(target) => replies.append(&mut whois_replies(irc, user, &target)),
+            None => {
+                replies.push(Err(ircError::NoSuchNick(nick.to_string())));
+                replies.push(Ok(ircReply::EndofWhois(nick.to_string())));
+            },
+        }
+    }
+    Ok(replies)
+}
+
+/* channels the viewer isn't allowed to know about are left out of 319,
+ * which is split up like RPL_NAMREPLY if they're on a lot of them */
+fn whois_replies(irc: &Core, viewer: &User, target: &User) -> ClientReplies {
+    let nick = target.get_nick();
+    let multi_prefix = viewer.has_cap("multi-prefix");
+    let channels = target.get_channel_list()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .filter(|chan| chan.is_visible_to(viewer) && (!chan.has_mode('A') || viewer.id == target.id))
+        .map(|chan| format!("{}{}", chan.member_prefixes(&nick, multi_prefix), chan.get_name()))
+        .collect::<Vec<_>>();
+    let mut replies = vec![Ok(ircReply::WhoisUser(nick.clone(), target.get_username(), target.get_host_string(), target.get_realname()))];
+    let overhead = format!(":{} 319 {} {} :", irc.get_host(), viewer.get_nick(), nick).len() + 2;
+    let room = rfc::MAX_MSG_SIZE.saturating_sub(overhead);
+    let mut line: Vec<String> = Vec::new();
+    let mut line_len = 0;
+    for chan in channels {
+        if !line.is_empty() && line_len + 1 + chan.len() > room {
+            replies.push(Ok(ircReply::WhoisChannels(nick.clone(), std::mem::take(&mut line))));
+            line_len = 0;
+        }
+        line_len += if line.is_empty() { chan.len() } else { chan.len() + 1 };
+        line.push(chan);
+    }
+    if !line.is_empty() {
+        replies.push(Ok(ircReply::WhoisChannels(nick.clone(), line)));
+    }
+    replies.push(Ok(ircReply::WhoisServer(nick.clone(), target.get_server(), irc.get_network())));
+    if target.is_oper() {
+        replies.push(Ok(ircReply::WhoisOperator(nick.clone())));
+    }
+    if let Some(account) = target.get_account() {
+        replies.push(Ok(ircReply::WhoisAccount(nick.clone(), account)));
+    }
+    if target.is_tls() {
+        replies.push(Ok(ircReply::WhoisSecure(nick.clone())));
+    }
+    replies.push(Ok(ircReply::WhoisIdle(nick.clone(), target.get_idle(), target.get_signon())));
+    replies.push(Ok(ircReply::EndofWhois(nick)));
+    replies
+}
+
+/* WHOWAS nick[,nick...] [count] - 312 carries when they left rather
+ * than a server description */
+pub fn whowas(irc: &Core, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let targets = match params.opt_params.first() {
+        Some(targets) => targets,
+        None => return gef!(ircError::NoNickNameGiven),
+    };
+    let count = params.opt_params.get(1).and_then(|count| count.parse::<usize>().ok()).unwrap_or(0);
+    let mut replies = Vec::new();
+    for nick in targets.split(',').filter(|nick| !nick.is_empty()) {
+        let entries = irc.find_whowas(nick, count);
+        if entries.is_empty() {
+            replies.push(Err(ircError::WasNoSuchNick(nick.to_string())));
+        }
+        for entry in entries {
+            let departed = Utc.timestamp_opt(entry.departed, 0).single().map_or_else(String::new, |time| time.to_rfc2822());
+            replies.push(Ok(ircReply::WhowasUser(entry.nick.clone(), entry.username, entry.host, entry.real_name)));
+            replies.push(Ok(ircReply::WhoisServer(entry.nick, entry.server, departed)));
+        }
+        replies.push(Ok(ircReply::EndofWhowas(nick.to_string())));
+    }
+    Ok(replies)
+}
+
+/* WHO #chan lists the members the asker can see (none if it's +s or +p
+ * and they're not in it, only themselves on a +A channel), anything else
+ * is a mask for everyone who isn't hidden from them by +i */
+pub fn who(irc: &Core, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let mask = params.opt_params.first().map_or("*", |mask| &mask[..]);
+    let query = WhoQuery::parse(mask, params.opt_params.get(1).map(|options| &options[..]));
+    let mut replies = Vec::new();
+    let chan = if mask.starts_with(|c| rfc::CHANTYPES.contains(c)) {
+        irc.get_chan(mask).ok()
+    } else {
+        None
+    };
+    if let Some(chan) = chan {
+        if chan.is_visible_to(user) {
+            let joined = chan.is_joined(&user.get_nick());
+            let anonymous = chan.has_mode('A');
+            for member in chan.gen_user_ptr_vec() {
+                if (!joined && member.is_invisible())
+                    || (anonymous && member.id != user.id)
+                    || (query.opers_only && !member.is_oper()) {
+                    continue;
+                }
+                replies.push(Ok(query.reply(user, &member, Some(&chan))));
+            }
+        }
+    } else {
+        for other in irc.list_users_ptr() {
+            let visible = other.id == user.id || user.is_oper() || !other.is_invisible() || user.shares_channel(&other);
+            if visible && (!query.opers_only || other.is_oper()) && query.matches(&other) {
+                replies.push(Ok(query.reply(user, &other, None)));
+            }
+        }
+    }
+    replies.push(Ok(ircReply::EndofWho(mask.to_string())));
+    Ok(replies)
+}
+
+/* the ISUPPORT lines come along too, as they would after registering */
+pub fn version(irc: &Core, user: &User) -> Result<ClientReplies, GenError> {
+    let mut replies = vec![Ok(ircReply::Version(irc.get_version(), irc.get_host(), irc.get_network()))];
+    replies.append(&mut irc.isupport_replies(&user.get_nick()));
+    Ok(replies)
+}
+
+pub fn time(irc: &Core) -> Result<ClientReplies, GenError> {
+    Ok(vec![Ok(ircReply::Time(irc.get_host(), Utc::now().to_rfc2822()))])
+}
+
+pub fn admin(irc: &Core) -> Result<ClientReplies, GenError> {
+    let admin = match irc.get_admin() {
+        Some(admin) => admin,
+        None => return gef!(ircError::NoAdminInfo(irc.get_host())),
+    };
+    Ok(vec![
+        Ok(ircReply::AdminMe(irc.get_host())),
+        Ok(ircReply::AdminLoc1(admin.location)),
+        Ok(ircReply::AdminLoc2(admin.organisation)),
+        Ok(ircReply::AdminEmail(admin.email)),
+    ])
+}
+
+pub fn info(irc: &Core) -> Result<ClientReplies, GenError> {
+    let lines = vec![
+        format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        "an IRC daemon written in Rust".to_string(),
+        String::new(),
+        format!("Written by {}", env!("CARGO_PKG_AUTHORS")),
+        format!("Licensed under {}", env!("CARGO_PKG_LICENSE")),
+        String::new(),
+        format!("On-line since {}", irc.get_date()),
+    ];
+    let mut replies = lines.into_iter().map(|line| Ok(ircReply::Info(line))).collect::<Vec<_>>();
+    replies.push(Ok(ircReply::EndofInfo));
+    Ok(replies)
+}
+
+/* STATS u and m are anyone's to see, l shows opers every connection and
+ * everyone else their own, o is for opers only - anything else just
+ * gets the end of the (empty) report */
+pub fn stats(irc: &Core, client: &Arc<Client>, user: &User, params: ParsedMsg) -> Result<ClientReplies, GenError> {
+    let letter = match params.opt_params.first().and_then(|query| query.chars().next()) {
+        Some(letter) => letter,
+        None => return gef!(ircError::NeedMoreParams("STATS".to_string())),
+    };
+    let mut replies = Vec::new();
+    match letter {
+        'u' => replies.push(Ok(ircReply::StatsUptime(irc.get_uptime()))),
+        'm' => {
+            for (cmd, count, bytes) in irc.get_command_stats() {
+                replies.push(Ok(ircReply::StatsCommands(cmd, count, bytes)));
+            }
+        },
+        'l' => {
+            let clients = if user.is_oper() {
+                irc.list_clients_ptr()
+            } else {
+                vec![Arc::clone(client)]
+            };
+            let now = Utc::now().timestamp();
+            for conn in clients {
+                let name = match conn.get_client_type() {
+                    ClientType::User(user) => format!("{}[{}@{}]", user.get_nick(), user.get_username(), conn.get_host_string()),
+                    _ => format!("*[unknown@{}]", conn.get_host_string()),
+                };
+                let stats = conn.get_link_stats();
+                replies.push(Ok(ircReply::StatsLinkInfo(
+                    name,
+                    stats.sendq,
+                    stats.sent_msgs,
+                    stats.sent_bytes / 1024,
+                    stats.recv_msgs,
+                    stats.recv_bytes / 1024,
+                    now - conn.get_connected(),
+                )));
+            }
+        },
+        'o' if !user.is_oper() => return gef!(ircError::NoPrivileges),
+        'o' => {
+            for name in irc.get_oper_names() {
+                replies.push(Ok(ircReply::StatsOLine(name)));
+            }
+        },
+        _ => (),
+    }
+    replies.push(Ok(ircReply::EndofStats(letter)));
+    Ok(replies)
+}
+
 pub async fn nick(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result<ClientReplies, GenError> {
     let mut replies = Vec::new();
     let nick;
-    if let Some(n) = params.opt_params.iter().next() {
+    if let Some(n) = params.opt_params.first() {
         nick = n.to_string();
     } else {
         replies.push(Err(ircError::NeedMoreParams("NICK".to_string())));
@@ -792,13 +2264,20 @@ pub async fn nick(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
     }
 
     // is the nick a valid nick string?
-    if !rfc::valid_nick(&nick) {
+    if !rfc::valid_nick(&nick) || nick.len() > irc.get_limits().nick_len {
         replies.push(Err(ircError::ErroneusNickname(nick)));
         return Ok(replies);
     }
 
-    // is this nick already taken?
-    if let Some(_hit) = irc.get_name(&nick) {
+    // asking for the nick we've already got changes nothing
+    if matches!(client.get_client_type(), ClientType::User(user) if user.get_nick() == nick) {
+        return Ok(replies);
+    }
+
+    // is this nick already taken? (by someone other than us changing case)
+    let recasing = matches!(client.get_client_type(),
+        ClientType::User(user) if irc.casefold(&user.get_nick()) == irc.casefold(&nick));
+    if !recasing && irc.get_name(&nick).is_some() {
         replies.push(Err(ircError::NicknameInUse(nick)));
         return Ok(replies);
     }
@@ -817,20 +2296,31 @@ pub async fn nick(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
             }))))
         }
         ClientType::User(user_ref) => {
-            // just a nick change
+            // just a nick change, as long as no channel objects
+            if let Some(chan) = user_ref.nick_change_blocked() {
+                replies.push(Err(ircError::BanNickChange(chan)));
+                return Ok(replies);
+            }
+            let old_prefix = user_ref.get_prefix();
             user_ref.change_nick(&nick)?;
+            let line = format!(":{} NICK :{}", old_prefix, nick);
+            user_ref.send_line(&line).await?;
+            user_ref.notify_peers(&line).await;
             None
         }
         ClientType::ProtoUser(proto_user_ref) => {
             // in this case we already got USER
             let mut proto_user = proto_user_ref.lock().unwrap();
             // need to account for the case where NICK is sent
-            // twice without any user command
-            if proto_user.nick.is_some() {
+            // twice without any user command, or while CAP holds
+            // registration up - but once we've had USER and CAP is
+            // done, a NICK after a failed attempt has to register
+            if proto_user.username.is_none() || client.is_cap_negotiating() {
                 proto_user.nick = Some(nick);
                 None
             } else {
                 // full registration! wooo
+                proto_user.nick = Some(nick.clone());
                 let username = proto_user.username.as_ref();
                 let real_name = proto_user.real_name.as_ref();
                 let ret = Some(ClientType::User(
@@ -841,10 +2331,7 @@ pub async fn nick(irc: &Core, client: &Arc<Client>, params: ParsedMsg) -> Result
                         real_name.unwrap().to_string(),
                     )?, // error propagation if registration fails
                 ));
-                replies.push(Ok(ircReply::Welcome(nick.clone(), username.unwrap().clone(), client.get_host_string())));
-                replies.push(Ok(ircReply::YourHost(irc.get_host(), irc.get_version())));
-                replies.push(Ok(ircReply::Created(irc.get_date())));
-                replies.push(Ok(ircReply::MyInfo(irc.get_host(), irc.get_version(), irc.get_umodes(), irc.get_chanmodes())));
+                replies.append(&mut welcome_burst(irc, client, &nick, username.unwrap()));
                 ret
             }
         }
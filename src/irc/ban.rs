@@ -0,0 +1,232 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+extern crate chrono;
+use crate::irc::rfc_defs as rfc;
+use crate::irc::User;
+use chrono::Utc;
+
+/* extended bans look like ~a:account, the letter says what gets
+ * matched instead of nick!user@host:
+ *   ~a[:account]  logged in (to a matching account)
+ *   ~j:#chan      on another channel
+ *   ~q:mask       quiet, only stops them speaking - may wrap another extban
+ *   ~r:realname   real name matches
+ *   ~t:mins:mask  mask that lifts itself after mins minutes
+ *   ~z            connected without TLS */
+pub const EXTBAN_PREFIX: char = '~';
+pub const EXTBAN_TYPES: &str = "ajqrtz";
+
+/* a year's worth of minutes, anyone wanting longer can set a real ban */
+pub const MAX_TIMED_MINUTES: u32 = 366 * 24 * 60;
+
+/* one entry on a +b, +e or +I list, with who set it and when */
+#[derive(Debug, Clone)]
+pub struct ListEntry {
+    pub mask: String,
+    pub set_by: String,
+    pub timestamp: i64,
+    pub expires: Option<i64>,
+}
+
+impl ListEntry {
+    pub fn new(mask: &str, set_by: &str) -> Self {
+        let timestamp = Utc::now().timestamp();
+        ListEntry {
+            mask: mask.to_string(),
+            set_by: set_by.to_string(),
+            timestamp,
+            /* normalize_mask() keeps mins in bounds, this is just so a
+             * bad one can never overflow into a ban that's already gone */
+            expires: timed_minutes(mask)
+                .and_then(|mins| i64::from(mins).checked_mul(60))
+                .and_then(|secs| timestamp.checked_add(secs)),
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| now >= expires)
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        is_quiet(&self.mask)
+    }
+
+    pub fn matches(&self, user: &User) -> bool {
+        !self.is_expired(Utc::now().timestamp()) && mask_matches(&self.mask, user)
+    }
+}
+
+/* "~a:bob" gives ('a', Some("bob")), "~z" gives ('z', None) */
+fn split_extban(mask: &str) -> Option<(char, Option<&str>)> {
+    let rest = mask.strip_prefix(EXTBAN_PREFIX)?;
+    let mut chars = rest.chars();
+    let kind = chars.next()?;
+    match chars.as_str() {
+        "" => Some((kind, None)),
+        arg => arg.strip_prefix(':').map(|arg| (kind, Some(arg))),
+    }
+}
+
+/* ~t:mins:mask splits into the minutes and the mask underneath */
+fn split_timed(mask: &str) -> Option<(u32, &str)> {
+    match split_extban(mask) {
+        Some(('t', Some(arg))) => {
+            let (mins, inner) = arg.split_at(arg.find(':')?);
+            Some((mins.parse().ok()?, &inner[1..]))
+        },
+        _ => None,
+    }
+}
+
+fn timed_minutes(mask: &str) -> Option<u32> {
+    split_timed(mask).map(|(mins, _inner)| mins)
+}
+
+fn is_quiet(mask: &str) -> bool {
+    match split_timed(mask) {
+        Some((_mins, inner)) => is_quiet(inner),
+        None => matches!(split_extban(mask), Some(('q', Some(_)))),
+    }
+}
+
+/* checked against both the host we show and the address they
+ * connected from, so banning an IP works even once it resolves */
+fn mask_matches(mask: &str, user: &User) -> bool {
+    match split_extban(mask) {
+        None => {
+            let nick_user = format!("{}!{}@", user.get_nick(), user.get_username());
+            rfc::mask_match(mask, &format!("{}{}", nick_user, user.get_host_string()))
+                || rfc::mask_match(mask, &format!("{}{}", nick_user, user.get_ip()))
+        },
+        Some(('a', None)) => user.get_account().is_some(),
+        Some(('a', Some(account))) => user.get_account().is_some_and(|acct| rfc::mask_match(account, &acct)),
+        Some(('j', Some(chan))) => user.irc.get_chan(chan).is_ok_and(|chan| chan.is_joined(&user.get_nick())),
+        Some(('q', Some(inner))) => mask_matches(inner, user),
+        Some(('r', Some(real_name))) => rfc::mask_match(real_name, &user.get_realname()),
+        Some(('t', Some(_arg))) => split_timed(mask).is_some_and(|(_mins, inner)| mask_matches(inner, user)),
+        Some(('z', None)) => !user.is_tls(),
+        _ => false,
+    }
+}
+
+/* fill in whatever parts of nick!user@host were left out, the way
+ * people tend to type them: "nick", "user@host" or "some.host" -
+ * extbans are checked over instead, None if they make no sense */
+pub fn normalize_mask(mask: &str) -> Option<String> {
+    match split_extban(mask) {
+        None if mask.is_empty() || mask.contains(' ') => None,
+        None => Some(normalize_hostmask(mask)),
+        Some(('a', None)) | Some(('z', None)) => Some(mask.to_string()),
+        Some(('a', Some(arg))) | Some(('r', Some(arg))) if !arg.is_empty() => Some(mask.to_string()),
+        Some(('j', Some(chan))) if rfc::valid_channel(chan) => Some(mask.to_string()),
+        Some(('q', Some(inner))) => normalize_mask(inner).map(|inner| format!("~q:{}", inner)),
+        Some(('t', Some(_arg))) => {
+            let (mins, inner) = split_timed(mask)?;
+            if mins == 0 || mins > MAX_TIMED_MINUTES {
+                return None;
+            }
+            normalize_mask(inner).map(|inner| format!("~t:{}:{}", mins, inner))
+        },
+        _ => None,
+    }
+}
+
+fn normalize_hostmask(mask: &str) -> String {
+    let (nick_user, host) = match mask.find('@') {
+        Some(at) => (&mask[..at], &mask[at + 1..]),
+        None if mask.contains('.') || mask.contains(':') => ("", mask),
+        None => (mask, ""),
+    };
+    let (nick, user) = match nick_user.find('!') {
+        Some(bang) => (&nick_user[..bang], &nick_user[bang + 1..]),
+        None if mask.contains('@') => ("", nick_user),
+        None => (nick_user, ""),
+    };
+    let or_star = |part: &str| if part.is_empty() { "*".to_string() } else { part.to_string() };
+    format!("{}!{}@{}", or_star(nick), or_star(user), or_star(host))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normal(mask: &str) -> Option<String> {
+        normalize_mask(mask)
+    }
+
+    #[test]
+    fn hostmasks_filled_in() {
+        assert_eq!(normal("bob").as_deref(), Some("bob!*@*"));
+        assert_eq!(normal("bob!user").as_deref(), Some("bob!user@*"));
+        assert_eq!(normal("user@host").as_deref(), Some("*!user@host"));
+        assert_eq!(normal("some.host").as_deref(), Some("*!*@some.host"));
+        assert_eq!(normal("::1").as_deref(), Some("*!*@::1"));
+        assert_eq!(normal("bob!user@host").as_deref(), Some("bob!user@host"));
+        assert_eq!(normal("@host").as_deref(), Some("*!*@host"));
+        assert_eq!(normal(""), None);
+        assert_eq!(normal("bob user"), None);
+    }
+
+    #[test]
+    fn extbans_checked() {
+        assert_eq!(normal("~a").as_deref(), Some("~a"));
+        assert_eq!(normal("~a:bob").as_deref(), Some("~a:bob"));
+        assert_eq!(normal("~a:"), None);
+        assert_eq!(normal("~z").as_deref(), Some("~z"));
+        assert_eq!(normal("~z:x"), None);
+        assert_eq!(normal("~r:*bot*").as_deref(), Some("~r:*bot*"));
+        assert_eq!(normal("~j:#chan").as_deref(), Some("~j:#chan"));
+        assert_eq!(normal("~j:chan"), None);
+        assert_eq!(normal("~j"), None);
+        assert_eq!(normal("~x:whatever"), None);
+    }
+
+    #[test]
+    fn quiets_wrap_other_masks() {
+        assert_eq!(normal("~q:bob").as_deref(), Some("~q:bob!*@*"));
+        assert_eq!(normal("~q:~a:bob").as_deref(), Some("~q:~a:bob"));
+        assert_eq!(normal("~q:~x"), None);
+        assert!(is_quiet("~q:bob!*@*"));
+        assert!(is_quiet("~t:5:~q:bob!*@*"));
+        assert!(!is_quiet("~t:5:bob!*@*"));
+        assert!(!is_quiet("bob!*@*"));
+    }
+
+    #[test]
+    fn timed_bans_bounded() {
+        assert_eq!(normal("~t:5:bob").as_deref(), Some("~t:5:bob!*@*"));
+        assert_eq!(normal("~t:5:~q:bob").as_deref(), Some("~t:5:~q:bob!*@*"));
+        let max = format!("~t:{}:bob", MAX_TIMED_MINUTES);
+        assert_eq!(normal(&max), Some(format!("{}!*@*", max)));
+        assert_eq!(normal(&format!("~t:{}:bob", MAX_TIMED_MINUTES + 1)), None);
+        assert_eq!(normal("~t:999999999999999999:bob"), None);
+        assert_eq!(normal("~t:0:bob"), None);
+        assert_eq!(normal("~t:-5:bob"), None);
+        assert_eq!(normal("~t:x:bob"), None);
+        assert_eq!(normal("~t:5"), None);
+        assert_eq!(normal("~t:5:~x"), None);
+    }
+
+    #[test]
+    fn timed_entries_expire() {
+        let entry = ListEntry::new("~t:5:bob!*@*", "alice");
+        assert_eq!(entry.expires, Some(entry.timestamp + 300));
+        assert!(!entry.is_expired(entry.timestamp + 299));
+        assert!(entry.is_expired(entry.timestamp + 300));
+        assert_eq!(ListEntry::new("bob!*@*", "alice").expires, None);
+    }
+}
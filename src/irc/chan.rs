@@ -4,10 +4,12 @@ use crate::client::{ClientReply, ClientReplies, GenError};
 use crate::irc::error::Error as ircError;
 use crate::irc::reply::Reply as ircReply;
 use crate::irc::{Core, User};
+use crate::irc::ban::ListEntry;
+use crate::irc::rfc_defs as rfc;
 
 use chrono::Utc;
 use std::clone::Clone;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::{error, fmt};
 use std::sync::{Arc, Mutex, Weak};
 
@@ -29,26 +31,88 @@ impl fmt::Display for ChanError {
     }
 }
 
-#[derive(Debug, Clone)]
+/* what everyone else sees a member of a +A channel as */
+pub const ANONYMOUS_PREFIX: &str = "anonymous!anonymous@anonymous.";
+pub const ANONYMOUS_NICK: &str = "anonymous";
+
+/* channel ranks, ordered lowest to highest, so the last one in a
+ * user's set is the badge they get in NAMES - a member can hold any
+ * number of them at once */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum ChanFlags {
-    None,
     Voice,
+    HalfOp,
     Op,
+    Admin,
+    Owner,
+}
+
+/* highest first, the order PREFIX advertises them in */
+pub const CHAN_RANKS: [ChanFlags; 5] = [
+    ChanFlags::Owner,
+    ChanFlags::Admin,
+    ChanFlags::Op,
+    ChanFlags::HalfOp,
+    ChanFlags::Voice,
+];
+
+impl ChanFlags {
+    pub fn prefix(&self) -> char {
+        match self {
+            ChanFlags::Voice => '+',
+            ChanFlags::HalfOp => '%',
+            ChanFlags::Op => '@',
+            ChanFlags::Admin => '&',
+            ChanFlags::Owner => '~',
+        }
+    }
+
+    pub fn mode(&self) -> char {
+        match self {
+            ChanFlags::Voice => 'v',
+            ChanFlags::HalfOp => 'h',
+            ChanFlags::Op => 'o',
+            ChanFlags::Admin => 'a',
+            ChanFlags::Owner => 'q',
+        }
+    }
+
+    /* which ranks somebody holding this one may hand out or take away:
+     * halfops only voice, ops manage everything up to op, admins up to
+     * admin, and only owners make owners */
+    pub fn can_grant(&self, rank: ChanFlags) -> bool {
+        match self {
+            ChanFlags::Voice => false,
+            ChanFlags::HalfOp => rank == ChanFlags::Voice,
+            _ => rank <= *self,
+        }
+    }
+}
+
+/* the ISUPPORT PREFIX token, "(qaohv)~&@%+" */
+pub fn prefix_token() -> String {
+    let modes = CHAN_RANKS.iter().map(ChanFlags::mode).collect::<String>();
+    let prefixes = CHAN_RANKS.iter().map(ChanFlags::prefix).collect::<String>();
+    format!("PREFIX=({}){}", modes, prefixes)
 }
 
 #[derive(Debug, Clone)]
 pub struct ChanUser {
     user_ptr: Weak<User>,
-    chan_flags: ChanFlags,
+    chan_flags: BTreeSet<ChanFlags>,
 }
 
 impl ChanUser {
-    pub fn new(user: &Arc<User>, flags: ChanFlags) -> ChanUser {
+    pub fn new(user: &Arc<User>, flags: &[ChanFlags]) -> ChanUser {
         ChanUser {
-            user_ptr: Arc::downgrade(&user),
-            chan_flags: flags,
+            user_ptr: Arc::downgrade(user),
+            chan_flags: flags.iter().cloned().collect(),
         }
     }
+
+    pub fn highest_flag(&self) -> Option<ChanFlags> {
+        self.chan_flags.iter().next_back().cloned()
+    }
 }
 
 #[derive(Debug)]
@@ -73,7 +137,15 @@ pub struct Channel {
     name: String,
     topic: Mutex<Option<ChanTopic>>,
     users: Mutex<BTreeMap<String, ChanUser>>,
-    banmasks: Mutex<Vec<String>>,
+    /* the +b, +e and +I lists, keyed by mode letter */
+    banmasks: Mutex<HashMap<char, Vec<ListEntry>>>,
+    flags: Mutex<BTreeSet<char>>,
+    key: Mutex<Option<String>>,
+    limit: Mutex<Option<usize>>,
+    /* user id to when their INVITE runs out */
+    invites: Mutex<HashMap<u64, i64>>,
+    last_knock: Mutex<i64>,
+    created: i64,
     irc: Arc<Core>,
 }
 
@@ -82,16 +154,36 @@ impl Channel {
         let name = chanmask.to_string();
         let topic = Mutex::new(None);
         let users = Mutex::new(BTreeMap::new());
-        let banmasks = Mutex::new(Vec::new());
+        let banmasks = Mutex::new(HashMap::new());
+        /* new channels start out +nt, like most networks - apart from
+         * +channels, which have no modes at all bar a +t nobody can
+         * get around, since nobody's an op there either */
+        let flags = if name.starts_with('+') {
+            Mutex::new(['t'].iter().cloned().collect())
+        } else {
+            Mutex::new(['n', 't'].iter().cloned().collect())
+        };
         Channel {
             name,
             topic,
             users,
             banmasks,
-            irc: Arc::clone(&irc)
+            flags,
+            key: Mutex::new(None),
+            limit: Mutex::new(None),
+            invites: Mutex::new(HashMap::new()),
+            last_knock: Mutex::new(0),
+            created: Utc::now().timestamp(),
+            irc: Arc::clone(irc)
         }
     }
 
+    /* members are keyed by their casefolded nick, Foo and foo being the
+     * same person - anything shown to users wants User::get_nick() */
+    fn key(&self, nick: &str) -> String {
+        self.irc.casefold(nick)
+    }
+
     /* spit out a vector of (key, value) tuples */
     fn _get_user_list(&self) -> Vec<(String, ChanUser)> {
         self.users
@@ -126,8 +218,8 @@ impl Channel {
     fn _get_nick_list_wo_badges(&self) -> Vec<String> {
         self._get_user_list()
             .iter()
-            .map(|(key, _val)|{
-                key.to_string()
+            .filter_map(|(_key, val)|{
+                Some(Weak::upgrade(&val.user_ptr)?.get_nick())
             }).collect::<Vec<_>>()
     }
 
@@ -136,33 +228,92 @@ impl Channel {
     pub fn get_nick_list(&self) -> Vec<String> {
         self._get_user_list()
             .iter()
-            .map(|(key, val)| {
-                match val.chan_flags {
-                    ChanFlags::None => key.to_string(),
-                    ChanFlags::Voice => format!("+{}", key).to_string(),
-                    ChanFlags::Op => format!("@{}", key).to_string(),
-                }
+            .filter_map(|(_key, val)| {
+                let nick = Weak::upgrade(&val.user_ptr)?.get_nick();
+                Some(match val.highest_flag() {
+                    None => nick,
+                    Some(flag) => format!("{}{}", flag.prefix(), nick),
+                })
             }).collect::<Vec<_>>()
     }
 
+    /* RPL_NAMREPLY lines for this viewer, each cut to fit the 512 byte
+     * limit at a nick boundary - outsiders don't get to see +i users,
+     * and the multi-prefix and userhost-in-names caps are honoured */
+    pub fn names_replies(&self, viewer: &User) -> ClientReplies {
+        let name = self.get_name();
+        let symbol = self.get_names_symbol();
+        let joined = self.is_joined(&viewer.get_nick());
+        let anonymous = self.has_mode('A');
+        let multi_prefix = viewer.has_cap("multi-prefix");
+        let userhost = viewer.has_cap("userhost-in-names");
+        let entries = self._get_user_list()
+            .into_iter()
+            .filter_map(|(_key, chan_user)| {
+                let user = Weak::upgrade(&chan_user.user_ptr)?;
+                if !joined && user.is_invisible() {
+                    return None;
+                }
+                /* on a +A channel you only get to see yourself */
+                if anonymous && user.id != viewer.id {
+                    return None;
+                }
+                let badges = if multi_prefix {
+                    chan_user.chan_flags.iter().rev().map(ChanFlags::prefix).collect::<String>()
+                } else {
+                    chan_user.highest_flag().map(|flag| flag.prefix().to_string()).unwrap_or_default()
+                };
+                let name = if userhost { user.get_prefix() } else { user.get_nick() };
+                Some(format!("{}{}", badges, name))
+            }).collect::<Vec<_>>();
+
+        /* ":server 353 nick = #chan :" plus the CRLF is all overhead */
+        let overhead = format!(":{} 353 {} {} {} :", self.irc.get_host(), viewer.get_nick(), symbol, name).len() + 2;
+        let room = rfc::MAX_MSG_SIZE.saturating_sub(overhead);
+        let mut replies = Vec::new();
+        let mut line: Vec<String> = Vec::new();
+        let mut line_len = 0;
+        for entry in entries {
+            if !line.is_empty() && line_len + 1 + entry.len() > room {
+                replies.push(Ok(ircReply::NameReply(symbol, name.clone(), std::mem::take(&mut line))));
+                line_len = 0;
+            }
+            line_len += if line.is_empty() { entry.len() } else { entry.len() + 1 };
+            line.push(entry);
+        }
+        if !line.is_empty() {
+            replies.push(Ok(ircReply::NameReply(symbol, name.clone(), line)));
+        }
+        replies.push(Ok(ircReply::EndofNames(name)));
+        replies
+    }
+
     pub fn get_n_users(&self) -> usize {
         self.users.lock().unwrap().len()
     }
 
     pub fn get_topic(&self) -> Option<ChanTopic> {
-        match self.topic.lock().unwrap().clone() {
-            Some(topic) => Some(topic.clone()),
-            None => None
-        }
+        self.topic.lock().unwrap().clone()
     }
 
+    /* an empty topic_text clears it, and on a +A channel nobody gets
+     * to find out from 333 who set it */
     pub fn set_topic(&self, topic_text: &str, user: &User) {
-        let topic = ChanTopic {
-            text: topic_text.to_string(),
-            usermask: user.get_prefix(),
-            timestamp: Utc::now().timestamp()
+        let usermask = if self.has_mode('A') {
+            ANONYMOUS_PREFIX.to_string()
+        } else {
+            user.get_prefix()
+        };
+        let topic = if topic_text.is_empty() {
+            None
+        } else {
+            Some(ChanTopic {
+                text: topic_text.to_string(),
+                usermask,
+                timestamp: Utc::now().timestamp()
+            })
         };
-        *self.topic.lock().unwrap() = Some(topic);
+        *self.topic.lock().unwrap() = topic;
     }
 
     pub fn get_name(&self) -> String {
@@ -177,37 +328,371 @@ impl Channel {
         self.users.lock().unwrap().is_empty()
     }
 
+    pub fn get_created(&self) -> i64 {
+        self.created
+    }
+
+    /* the RFC 2811 channel types: #channels are the usual, &channels
+     * are the same but would stay on this server once there's linking,
+     * +channels have no modes or ops, and !channels are "safe" */
+    pub fn is_modeless(&self) -> bool {
+        self.name.starts_with('+')
+    }
+
+    pub fn is_safe(&self) -> bool {
+        self.name.starts_with('!')
+    }
+
+    /* a safe channel's name without the '!' and the 5 character ID */
+    pub fn get_short_name(&self) -> Option<&str> {
+        if self.is_safe() {
+            self.name.get(6..)
+        } else {
+            None
+        }
+    }
+
+    /* +A (anonymous) and +r (reop) only mean anything on safe channels */
+    pub fn supports_mode(&self, mode: char) -> bool {
+        !self.is_modeless() && (self.is_safe() || (mode != 'A' && mode != 'r'))
+    }
+
+    /* how the source of a line appears to the member receiving it, on a
+     * +A channel that's anonymous to everyone but themselves */
+    fn prefix_for(&self, source: &User, viewer: &User) -> String {
+        if self.has_mode('A') && viewer.id != source.id {
+            ANONYMOUS_PREFIX.to_string()
+        } else {
+            source.get_prefix()
+        }
+    }
+
+    /* the same as prefix_for(), for when a member is named by nick */
+    fn nick_for(&self, user: &User, viewer: &User) -> String {
+        if self.has_mode('A') && viewer.id != user.id {
+            ANONYMOUS_NICK.to_string()
+        } else {
+            user.get_nick()
+        }
+    }
+
+    /* whether anyone's left who can run the place, for +r */
+    pub fn has_ops(&self) -> bool {
+        self.users.lock().unwrap().values().any(|chan_user| chan_user.highest_flag() >= Some(ChanFlags::Op))
+    }
+
+    /* a +r safe channel that's lost all its ops gets one back, the
+     * first member alphabetically is as good a pick as any - hands back
+     * the nick so the caller can tell the channel */
+    pub fn reop(&self) -> Option<String> {
+        if !self.is_safe() || !self.has_mode('r') || self.has_ops() {
+            return None;
+        }
+        let nick = self.users.lock().unwrap().keys().next()?.clone();
+        self.set_flag(&nick, ChanFlags::Op, true).ok()?
+    }
+
+    pub fn has_flag(&self, user: &User, flag: ChanFlags) -> bool {
+        match self.users.lock().unwrap().get(&self.key(&user.get_nick())) {
+            Some(chan_user) => chan_user.chan_flags.contains(&flag),
+            None => false,
+        }
+    }
+
+    /* the badge(s) a member wears, all of them for multi-prefix */
+    pub fn member_prefixes(&self, nick: &str, all: bool) -> String {
+        match self.users.lock().unwrap().get(&self.key(nick)) {
+            Some(chan_user) if all => chan_user.chan_flags.iter().rev().map(ChanFlags::prefix).collect(),
+            Some(chan_user) => chan_user.highest_flag().map(|flag| flag.prefix().to_string()).unwrap_or_default(),
+            None => String::new(),
+        }
+    }
+
+    /* the highest rank a member holds, None for non-members too */
+    pub fn get_rank(&self, nick: &str) -> Option<ChanFlags> {
+        self.users.lock().unwrap().get(&self.key(nick))?.highest_flag()
+    }
+
+    /* "at least a halfop" and so on - anything above a rank gets what
+     * that rank does */
+    pub fn has_rank(&self, user: &User, rank: ChanFlags) -> bool {
+        self.get_rank(&user.get_nick()).is_some_and(|held| held >= rank)
+    }
+
     pub fn is_op(&self, user: &User) -> bool {
-        let op = format!("@{}", &user.nick.lock().unwrap());
-        self.users.lock().unwrap().contains_key(&op)
+        self.has_rank(user, ChanFlags::Op)
+    }
+
+    pub fn is_halfop(&self, user: &User) -> bool {
+        self.has_rank(user, ChanFlags::HalfOp)
+    }
+
+    pub fn is_voiced(&self, user: &User) -> bool {
+        self.has_rank(user, ChanFlags::Voice)
+    }
+
+    /* give or take away a rank, Ok(Some(nick)) if anything changed -
+     * the nick being the member's own casing, not whatever was typed */
+    pub fn set_flag(&self, nick: &str, flag: ChanFlags, set: bool) -> Result<Option<String>, ircError> {
+        let mut users = self.users.lock().unwrap();
+        let chan_user = users.get_mut(&self.key(nick))
+            .ok_or_else(|| ircError::UserNotInChannel(nick.to_string(), self.get_name()))?;
+        let changed = if set {
+            chan_user.chan_flags.insert(flag)
+        } else {
+            chan_user.chan_flags.remove(&flag)
+        };
+        let display = Weak::upgrade(&chan_user.user_ptr).map_or_else(|| nick.to_string(), |user| user.get_nick());
+        Ok(if changed { Some(display) } else { None })
+    }
+
+    pub fn get_mask_list(&self, mode: char) -> Vec<ListEntry> {
+        self.banmasks.lock().unwrap().get(&mode).cloned().unwrap_or_default()
+    }
+
+    /* Ok(true) if the mask went on, Ok(false) if it was already there -
+     * masks are the same if they casefold the same, like the names they
+     * stand for */
+    pub fn add_mask(&self, mode: char, mask: &str, set_by: &str) -> Result<bool, ircError> {
+        let max_entries = self.irc.get_limits().max_list_entries;
+        let entry = ListEntry::new(mask, set_by);
+        let key = self.irc.casefold(mask);
+        let mut lists = self.banmasks.lock().unwrap();
+        let list = lists.entry(mode).or_default();
+        if list.iter().any(|entry| self.irc.casefold(&entry.mask) == key) {
+            return Ok(false);
+        }
+        if list.len() >= max_entries {
+            return Err(ircError::BanListFull(self.get_name(), mode));
+        }
+        list.push(entry);
+        Ok(true)
+    }
+
+    /* hands back the mask as it was stored, for the broadcast */
+    pub fn rm_mask(&self, mode: char, mask: &str) -> Option<String> {
+        let key = self.irc.casefold(mask);
+        let mut lists = self.banmasks.lock().unwrap();
+        let list = lists.get_mut(&mode)?;
+        let index = list.iter().position(|entry| self.irc.casefold(&entry.mask) == key)?;
+        Some(list.remove(index).mask)
+    }
+
+    /* matching a ~j extban looks at another channel, so don't do it
+     * with our own list locked */
+    fn find_matches(&self, mode: char, user: &User) -> Vec<ListEntry> {
+        self.get_mask_list(mode)
+            .into_iter()
+            .filter(|entry| entry.matches(user))
+            .collect()
+    }
+
+    pub fn mask_list_matches(&self, mode: char, user: &User) -> bool {
+        !self.find_matches(mode, user).is_empty()
+    }
+
+    /* a ban exception (+e) trumps any ban, quiets (~q) don't count
+     * as bans here, they only keep people quiet once they're in */
+    pub fn is_banned(&self, user: &User) -> bool {
+        self.find_matches('b', user).iter().any(|entry| !entry.is_quiet()) && !self.mask_list_matches('e', user)
+    }
+
+    pub fn is_quieted(&self, user: &User) -> bool {
+        self.find_matches('b', user).iter().any(|entry| entry.is_quiet()) && !self.mask_list_matches('e', user)
+    }
+
+    /* timed masks stop matching by themselves, this clears them off the
+     * lists so they can be announced as gone */
+    pub fn take_expired_masks(&self, now: i64) -> Vec<(char, String)> {
+        let mut expired = Vec::new();
+        for (mode, list) in self.banmasks.lock().unwrap().iter_mut() {
+            list.retain(|entry| {
+                if entry.is_expired(now) {
+                    expired.push((*mode, entry.mask.clone()));
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        expired
+    }
+
+    /* somebody who can't be heard here shouldn't be able to dodge it
+     * by switching nick, nor make noise on everyone's screens doing so */
+    pub fn blocks_nick_change(&self, user: &User) -> bool {
+        self.is_banned(user) || self.is_muted(user)
+    }
+
+    /* +m leaves only voiced users and ops with a voice, and banned or
+     * quieted users who are still here have to make do without one too */
+    pub fn is_muted(&self, user: &User) -> bool {
+        let privileged = self.is_voiced(user);
+        !privileged && (self.has_mode('m') || self.is_banned(user) || self.is_quieted(user))
+    }
+
+    /* the simple on/off modes, +imnOpRst */
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.flags.lock().unwrap().contains(&mode)
+    }
+
+    /* true if it made a difference */
+    pub fn set_mode(&self, mode: char, set: bool) -> bool {
+        let mut flags = self.flags.lock().unwrap();
+        if set {
+            flags.insert(mode)
+        } else {
+            flags.remove(&mode)
+        }
+    }
+
+    pub fn get_key(&self) -> Option<String> {
+        self.key.lock().unwrap().clone()
+    }
+
+    /* true if it made a difference, same goes for set_limit() */
+    pub fn set_key(&self, key: Option<String>) -> bool {
+        let mut current = self.key.lock().unwrap();
+        let changed = *current != key;
+        *current = key;
+        changed
+    }
+
+    pub fn get_limit(&self) -> Option<usize> {
+        *self.limit.lock().unwrap()
+    }
+
+    pub fn set_limit(&self, limit: Option<usize>) -> bool {
+        let mut current = self.limit.lock().unwrap();
+        let changed = *current != limit;
+        *current = limit;
+        changed
+    }
+
+    /* the key is only for members' eyes, everyone else sees +k alone */
+    pub fn get_mode_string(&self, show_key: bool) -> String {
+        let mut modes = String::from("+");
+        let mut params = Vec::new();
+        modes.extend(self.flags.lock().unwrap().iter());
+        if let Some(key) = self.get_key() {
+            modes.push('k');
+            if show_key {
+                params.push(key);
+            }
+        }
+        if let Some(limit) = self.get_limit() {
+            modes.push('l');
+            params.push(limit.to_string());
+        }
+        params.insert(0, modes);
+        params.join(" ")
+    }
+
+    /* +s and +p channels don't show up for people who aren't in them */
+    pub fn is_visible_to(&self, user: &User) -> bool {
+        !(self.has_mode('s') || self.has_mode('p')) || self.is_joined(&user.get_nick())
+    }
+
+    /* the channel type character RPL_NAMREPLY leads with */
+    pub fn get_names_symbol(&self) -> char {
+        if self.has_mode('s') {
+            '@'
+        } else if self.has_mode('p') {
+            '*'
+        } else {
+            '='
+        }
+    }
+
+    /* an invite is good for one JOIN before it runs out, anything
+     * that's already run out gets thrown away while we're here */
+    pub fn invite(&self, user: &User) {
+        let now = Utc::now().timestamp();
+        let expiry = self.irc.get_limits().invite_expiry as i64;
+        let mut invites = self.invites.lock().unwrap();
+        invites.retain(|_id, expires| *expires > now);
+        invites.insert(user.id, now + expiry);
+    }
+
+    pub fn is_invited(&self, user: &User) -> bool {
+        let now = Utc::now().timestamp();
+        self.invites.lock().unwrap().get(&user.id).is_some_and(|expires| *expires > now)
+    }
+
+    /* true if it's been long enough since the last KNOCK here, and if
+     * so this one counts as the last */
+    pub fn take_knock(&self, now: i64, delay: i64) -> bool {
+        let mut last_knock = self.last_knock.lock().unwrap();
+        if now - *last_knock < delay {
+            return false;
+        }
+        *last_knock = now;
+        true
+    }
+
+    /* everything that can keep somebody out, checked before they join -
+     * an INVITE gets you past +i, +k and +l but not bans */
+    pub fn check_join(&self, user: &User, key: Option<&str>) -> Result<(), ircError> {
+        if self.has_mode('O') && !user.is_oper() {
+            return Err(ircError::OperOnlyChan(self.get_name()));
+        }
+        if self.has_mode('R') && user.get_account().is_none() {
+            return Err(ircError::NeedReggedNick(self.get_name()));
+        }
+        if self.is_banned(user) {
+            return Err(ircError::BannedFromChan(self.get_name()));
+        }
+        if self.is_invited(user) {
+            return Ok(());
+        }
+        if self.has_mode('i') && !self.mask_list_matches('I', user) {
+            return Err(ircError::InviteOnlyChan(self.get_name()));
+        }
+        if let Some(chan_key) = self.get_key() {
+            if key != Some(chan_key.as_str()) {
+                return Err(ircError::BadChannelKey(self.get_name()));
+            }
+        }
+        if let Some(limit) = self.get_limit() {
+            if self.get_n_users() >= limit {
+                return Err(ircError::ChannelIsFull(self.get_name()));
+            }
+        }
+        Ok(())
+    }
+
+    /* +n keeps outsiders quiet, +m everyone without voice */
+    pub fn check_send(&self, user: &User) -> Result<(), ircError> {
+        let joined = self.is_joined(&user.get_nick());
+        if (!joined && self.has_mode('n')) || self.is_muted(user) {
+            Err(ircError::CannotSendToChan(self.get_name()))
+        } else {
+            Ok(())
+        }
     }
 
     pub fn is_joined(&self, nick: &str) -> bool {
-        self.users.lock().unwrap().contains_key(nick)
+        self.users.lock().unwrap().contains_key(&self.key(nick))
     }
 
     /* put add_ and rm_user() here together and have all the code to handle
-     * that in one place, both for User and Chan side - plus, mutex lock
-     * everything for the entire fn call */
-    pub async fn add_user(self: &Arc<Self>, new_user: &Arc<User>, flags: ChanFlags) -> Result<ClientReplies, GenError> {
+     * that in one place, both for User and Chan side */
+    pub async fn add_user(self: &Arc<Self>, new_user: &Arc<User>, flags: &[ChanFlags]) -> Result<ClientReplies, GenError> {
         let chan = self.get_name();
         let mut replies = Vec::new();
         {
             let mut chan_mutex_lock = self.users.lock().unwrap();
-            let mut user_mutex_lock = new_user.channel_list.lock().unwrap();
-            let nick = new_user.get_nick();
-            let chan = self.get_name();
-            let chan_ptr = Arc::downgrade(&self);
-
-            if !chan_mutex_lock.contains_key(&nick) {
-                chan_mutex_lock.insert(nick, ChanUser::new(new_user, flags));
-                user_mutex_lock.insert(chan, chan_ptr);
+            let nick = self.key(&new_user.get_nick());
 
-                
+            if let std::collections::btree_map::Entry::Vacant(e) = chan_mutex_lock.entry(nick) {
+                e.insert(ChanUser::new(new_user, flags));
             } else {
                 return Ok(replies) /* already on chan */
             }
-        } /* de-scope mutex locks */
+        } /* de-scope mutex lock, see _unlink_user() */
+        new_user.channel_list.lock().unwrap().insert(self.key(&chan), Arc::downgrade(self));
+        /* that's the invite used up */
+        self.invites.lock().unwrap().remove(&new_user.id);
 
         /* also self.notify_join() */
         replies.push(self.notify_join(new_user, &chan).await?);
@@ -215,53 +700,78 @@ impl Channel {
             replies.push(Ok(ircReply::Topic(chan.to_string(), topic.text)));
             replies.push(Ok(ircReply::TopicSetBy(chan.to_string(), topic.usermask, topic.timestamp)))
         }
-        replies.push(Ok(ircReply::NameReply(chan.to_string(), self.get_nick_list())));
-        replies.push(Ok(ircReply::EndofNames(chan.to_string())));
+        replies.append(&mut self.names_replies(new_user));
         Ok(replies)
     }
 
     /* still need this for User::drop() */
     pub fn rm_key(&self, key: &str) -> Option<ChanUser> {
-        self.users.lock().unwrap().remove(key)
+        self.users.lock().unwrap().remove(&self.key(key))
     }
 
     /* put add_ and rm_user() here together and have all the code to handle
-     * that in one place, both for User and Chan side - plus, mutex lock
-     * everything for the entire fn call */
+     * that in one place, both for User and Chan side */
     pub async fn rm_user(&self, user: &User, msg: &str) -> Result<(), ChanError> {
+        /* outsiders don't get to send the channel a PART, let alone one
+         * saying whatever they like */
+        if !self.is_joined(&user.get_nick()) {
+            return Err(ChanError::UnlinkFailed(self.key(&user.get_nick()), self.get_name()));
+        }
         /* Notify part msg */
-        if !self.is_empty() {
-            let _res = self.notify_part(user, &self.get_name(), msg).await;
+        let _res = self.notify_part(user, &self.get_name(), msg).await;
+        self._unlink_user(user)
+    }
+
+    /* everyone sees the KICK, the victim included, before they're
+     * taken off the channel the same way PART does it - with no reason
+     * given it's the kicker's nick, which +A has to hide as well */
+    pub async fn kick_user(&self, source: &User, target: &User, reason: Option<&str>) -> Result<(), ChanError> {
+        for user in self.gen_user_ptr_vec().iter() {
+            let reason = reason.map_or_else(|| self.nick_for(source, user), str::to_string);
+            let line = format!(":{} KICK {} {} :{}", self.prefix_for(source, user), self.name, self.nick_for(target, user), reason);
+            if let Err(err) = user.send_line(&line).await {
+                debug!("couldn't pass {} on to {}: {}", line, &user.get_nick(), err);
+            }
         }
+        self._unlink_user(target)
+    }
+
+    /* the member looked up by nick, if they're here and still alive */
+    pub fn get_member(&self, nick: &str) -> Option<Arc<User>> {
+        Weak::upgrade(&self.users.lock().unwrap().get(&self.key(nick))?.user_ptr)
+    }
 
-        let retval = {
+    /* drop the user from both sides of the link, and the channel too if
+     * that was the last of them. KICK gets here from someone else's task,
+     * so stick to the lock order: the namespace may be held while taking a
+     * channel's user list or a user's channel list (NICK does), but never
+     * the other way round, and those two lists are never held together -
+     * hence one lock at a time here, in add_user() and in clear_up() */
+    fn _unlink_user(&self, user: &User) -> Result<(), ChanError> {
+        let key = self.key(&user.get_nick());
+        let chan = self.get_name();
+        let now_empty = {
             let mut chan_mutex_lock = self.users.lock().unwrap();
-            let mut user_mutex_lock = user.channel_list.lock().unwrap();
-
-            let key = user.get_nick().to_string();
-            let chan = self.get_name();
-            if let Some(_val) = chan_mutex_lock.remove(&key) {
-                user_mutex_lock.remove(&chan);
-                if chan_mutex_lock.is_empty() {
-                    if let Err(err) = self.irc.remove_name(&chan) {
-                        warn!("error {} removing chan {} from hash - it doesn't exist", err, &chan);
-                    }
-                }
-                Ok(())
-            } else {
-                Err(ChanError::UnlinkFailed(key, chan))
+            if chan_mutex_lock.remove(&key).is_none() {
+                return Err(ChanError::UnlinkFailed(key, chan));
             }
+            chan_mutex_lock.is_empty()
         }; /* de-scope Mutex */
 
-        retval
+        user.channel_list.lock().unwrap().remove(&self.key(&chan));
+        if now_empty {
+            if let Err(err) = self.irc.remove_name(&chan) {
+                warn!("error {} removing chan {} from hash - it doesn't exist", err, &chan);
+            }
+        }
+        Ok(())
     }
 
     /* similar rationale to the above about linking and unlinking users to chans */
     pub fn update_nick(&self, old_nick: &str, new_nick: &str) -> Result<(), ircError> {
         let mut mutex_lock = self.users.lock().unwrap();
-        let key = old_nick.to_string();
-        if let Some(val) = mutex_lock.remove(&key) {
-            mutex_lock.insert(new_nick.to_string(), val);
+        if let Some(val) = mutex_lock.remove(&self.key(old_nick)) {
+            mutex_lock.insert(self.key(new_nick), val);
             Ok(())
         } else {
             Err(ircError::NotOnChannel(self.name.clone()))
@@ -275,38 +785,78 @@ impl Channel {
         target: &str,
         msg: &str
     ) -> Result<ClientReply, GenError> {
-        // checks for banmasks should be done-
-        // also whether the sending user is in the channel or not
-        let prefix = source.get_prefix();
-        let line = if msg.is_empty() {
-            format!(":{} {} {}", prefix, command_str, target)
-        } else {
-            format!(":{} {} {} :{}", prefix, command_str, target, msg)
-        };
-
-        if self.is_joined(&source.get_nick()) {
-            // if we clone the list, the true list could change while
-            // we're forwarding messages, but this keeps us thread safe
-            let users = self.gen_user_ptr_vec();
-            for user in users.iter() {
-                // if you're parting or joining, your own echoed message confirms success
-                if user.id != source.id || command_str == "JOIN" || command_str == "PART" {
-                    if let Err(err) = user.send_line(&line).await {
-                        debug!("another tasks's client died: {}, note dead key {}", err, &user.get_nick());
-                        //user.clear_chans_and_exit();
-                    }
+        // whether the source may speak here at all is send_msg()'s
+        // problem, by now we're just passing the line on
+
+        // if we clone the list, the true list could change while
+        // we're forwarding messages, but this keeps us thread safe
+        let users = self.gen_user_ptr_vec();
+        for user in users.iter() {
+            let prefix = self.prefix_for(source, user);
+            let line = if msg.is_empty() {
+                format!(":{} {} {}", prefix, command_str, target)
+            } else {
+                format!(":{} {} {} :{}", prefix, command_str, target, msg)
+            };
+            // if you're parting or joining, your own echoed message confirms success
+            if user.id != source.id || command_str == "JOIN" || command_str == "PART" {
+                if let Err(err) = user.send_line(&line).await {
+                    debug!("another tasks's client died: {}, note dead key {}", err, &user.get_nick());
+                    //user.clear_chans_and_exit();
                 }
             }
-            Ok(Ok(ircReply::None))
-        } else {
-            Ok(Err(ircError::CannotSendToChan(target.to_string())))
         }
+        Ok(Ok(ircReply::None))
     }
 
     pub async fn send_msg(&self, source: &User, cmd: &str, target: &str, msg: &str) -> Result<ClientReply, GenError> {
+        if let Err(err) = self.check_send(source) {
+            return Ok(Err(err));
+        }
         self._send_msg(source, cmd, target, msg).await
     }
 
+    /* to every member, source included - used for MODE, where the
+     * sender needs to see what actually got applied */
+    pub async fn broadcast(&self, line: &str) {
+        for user in self.gen_user_ptr_vec().iter() {
+            if let Err(err) = user.send_line(line).await {
+                debug!("couldn't pass {} on to {}: {}", line, &user.get_nick(), err);
+            }
+        }
+    }
+
+    /* a server NOTICE to the halfops and up, for INVITE and KNOCK */
+    pub async fn notice_ops(&self, source: &User, text: &str) {
+        let line = format!(":{} NOTICE @{} :{}", self.irc.get_host(), self.name, text);
+        for user in self.gen_user_ptr_vec().iter() {
+            if user.id == source.id || !self.is_halfop(user) {
+                continue;
+            }
+            if let Err(err) = user.send_line(&line).await {
+                debug!("couldn't pass {} on to {}: {}", line, &user.get_nick(), err);
+            }
+        }
+    }
+
+    pub async fn notify_mode(&self, source: &User, modes: &str) {
+        for user in self.gen_user_ptr_vec().iter() {
+            let line = format!(":{} MODE {} {}", self.prefix_for(source, user), self.name, modes);
+            if let Err(err) = user.send_line(&line).await {
+                debug!("couldn't pass {} on to {}: {}", line, &user.get_nick(), err);
+            }
+        }
+    }
+
+    pub async fn notify_topic(&self, source: &User, text: &str) {
+        for user in self.gen_user_ptr_vec().iter() {
+            let line = format!(":{} TOPIC {} :{}", self.prefix_for(source, user), self.name, text);
+            if let Err(err) = user.send_line(&line).await {
+                debug!("couldn't pass {} on to {}: {}", line, &user.get_nick(), err);
+            }
+        }
+    }
+
     pub async fn notify_join(&self, source: &User, chan: &str) -> Result<ClientReply, GenError> {
         self._send_msg(source, "JOIN", chan, "").await
     }
@@ -315,7 +865,21 @@ impl Channel {
         self._send_msg(source, "PART", chan, msg).await
     }
 
-    pub async fn notify_quit(&self, source: &User, chan: &str, msg: &str) -> Result<ClientReply, GenError> {
-        self._send_msg(source, "QUIT", chan, msg).await
+    /* QUIT and NICK aren't addressed to a channel, and somebody sharing
+     * several channels with the source should only see them once, so the
+     * caller passes the same `notified` set in for each channel */
+    pub async fn notify_peers(&self, source: &User, line: &str, notified: &mut HashSet<u64>) {
+        /* these would give away who was who on a +A channel */
+        if self.has_mode('A') {
+            return;
+        }
+        for user in self.gen_user_ptr_vec().iter() {
+            if user.id == source.id || !notified.insert(user.id) {
+                continue;
+            }
+            if let Err(err) = user.send_line(line).await {
+                debug!("couldn't pass {} on to {}: {}", line, &user.get_nick(), err);
+            }
+        }
     }
 }
@@ -41,16 +41,45 @@ impl fmt::Display for Error {
             Error::NoSuchNick(nick) => write!(f, "401 {} :No such nick/channel", nick),
             Error::NoSuchChannel(chan) => write!(f, "403 {} :No such channel", chan),
             Error::CannotSendToChan(chan) => write!(f, "404 {} :Cannot send to channel", chan),
+            Error::TooManyChannels(chan) => write!(f, "405 {} :You have joined too many channels", chan),
+            Error::WasNoSuchNick(nick) => write!(f, "406 {} :There was no such nickname", nick),
+            Error::TooManyTargets(target) => write!(f, "407 {} :Duplicate recipients. No channel joined", target),
+            Error::NoOrigin => write!(f, "409 :No origin specified"),
             Error::NoRecipient(cmd) => write!(f, "411 :No recipient given ({})", cmd),
             Error::NoTextToSend => write!(f, "412 :No text to send"),
+            Error::InvalidCapCmd(cmd) => write!(f, "410 {} :Invalid CAP command", cmd),
             Error::UnknownCommand(cmd) => write!(f, "421 {} :Unknown command", cmd),
+            Error::NoMotd => write!(f, "422 :MOTD File is missing"),
+            Error::NoAdminInfo(serv) => write!(f, "423 {} :No administrative info available", serv),
+            Error::NoNickNameGiven => write!(f, "431 :No nickname given"),
             Error::ErroneusNickname(nick) => write!(f, "432 {} :Erroneous nickname", nick),
             Error::NicknameInUse(nick) => write!(f, "433 {} :Nickname is already in use", nick),
+            Error::BanNickChange(chan) => write!(f, "435 {} :Cannot change nickname while banned or muted on channel", chan),
+            Error::UserNotInChannel(nick, chan) => write!(f, "441 {} {} :They aren't on that channel", nick, chan),
             Error::NotOnChannel(chan) => write!(f, "442 {} :You're not on that channel", chan),
+            Error::UserOnChannel(nick, chan) => write!(f, "443 {} {} :is already on channel", nick, chan),
             Error::NotRegistered => write!(f, "451 :You have not registered"),
             Error::NeedMoreParams(cmd) => write!(f, "461 {} :Not enough parameters", cmd),
             Error::AlreadyRegistred => write!(f, "462 :You may not reregister"),
+            Error::PasswdMismatch => write!(f, "464 :Password incorrect"),
+            Error::KeySet(chan) => write!(f, "467 {} :Channel key already set", chan),
+            Error::ChannelIsFull(chan) => write!(f, "471 {} :Cannot join channel (+l)", chan),
+            Error::InviteOnlyChan(chan) => write!(f, "473 {} :Cannot join channel (+i)", chan),
+            Error::BannedFromChan(chan) => write!(f, "474 {} :Cannot join channel (+b)", chan),
+            Error::BadChannelKey(chan) => write!(f, "475 {} :Cannot join channel (+k)", chan),
+            Error::BanListFull(chan, mode) => write!(f, "478 {} {} :Channel list is full", chan, mode),
+            Error::NoChanModes(chan) => write!(f, "477 {} :Channel doesn't support modes", chan),
+            Error::NeedReggedNick(chan) => write!(f, "477 {} :Cannot join channel (+R) - you need to be logged into your account", chan),
+            Error::UnknownMode(mode, chan) => write!(f, "472 {} :is unknown mode char to me for {}", mode, chan),
+            Error::NoPrivileges => write!(f, "481 :Permission Denied- You're not an IRC operator"),
             Error::ChanOPrivsNeeded(chan) => write!(f, "482 {} :You're not channel operator", chan),
+            Error::OperOnlyChan(chan) => write!(f, "520 {} :Cannot join channel (IRCops only)", chan),
+            Error::TooManyKnock(chan, why) => write!(f, "712 {} :Too many KNOCKs ({})", chan, why),
+            Error::ChanOpen(chan) => write!(f, "713 {} :Channel is open", chan),
+            Error::KnockOnChan(chan) => write!(f, "714 {} :You're already on that channel", chan),
+            Error::SaslFail => write!(f, "904 :Authentication failed"),
+            Error::UModeUnknownFlag => write!(f, "501 :Unknown MODE flag"),
+            Error::UsersDontMatch => write!(f, "502 :Cannot change mode for other users"),
             Error::InvalidCommand(cmd) => write!(f, "600 {} :Parser: invalid command", cmd),
             Error::InvalidHost(host) => write!(f, "601 {} :Parser: invalid host", host),
             Error::InvalidUser(user) => write!(f, "602 {} :Parser: invalid user", user),
@@ -70,25 +99,28 @@ pub enum Error {
     //    NoSuchServer(        NumReply, &'static str),
     NoSuchChannel(String),
     CannotSendToChan(String),
-    //    TooManyChannels(     NumReply, &'static str),
-    //    WasNoSuchNick(       NumReply, &'static str),
+    TooManyChannels(String),
+    WasNoSuchNick(String),
     //    TooManyTargets(      NumReply, &'static str),
-    //    NoOrigin(            NumReply, &'static str),
+    TooManyTargets(String),
+    NoOrigin,
     NoRecipient(String),
     NoTextToSend,
+    InvalidCapCmd(String),
     //    NoTopLevel(          NumReply, &'static str),
     //    WildTopLevel(        NumReply, &'static str),
     UnknownCommand(String),
-    //    NoMotd(              NumReply, &'static str),
-    //    NoAdminInfo(         NumReply, &'static str),
+    NoMotd,
+    NoAdminInfo(String),
     //    FileError(           NumReply, &'static str),
-    //    NoNickNameGiven(     NumReply, &'static str),
+    NoNickNameGiven,
     ErroneusNickname(String),
     NicknameInUse(String),
     //    NickCollision(       NumReply, &'static str),
-    //    UserNotInChannel(    NumReply, &'static str),
+    BanNickChange(String),
+    UserNotInChannel(String, String),
     NotOnChannel(String),
-    //    UserOnChannel(       NumReply, &'static str),
+    UserOnChannel(String, String),
     //    NoLogin(             NumReply, &'static str),
     //    SummonDisabled(      NumReply, &'static str),
     //    UsersDisabled(       NumReply, &'static str),
@@ -96,20 +128,28 @@ pub enum Error {
     NeedMoreParams(String),
     AlreadyRegistred,
     //    NoPermForHost(       NumReply, &'static str),
-    //    PasswdmisMatch(      NumReply, &'static str),
+    PasswdMismatch,
     //    YoureBannedCreep(    NumReply, &'static str),
-    //    KeySet(              NumReply, &'static str),
-    //    ChannelIsFull(       NumReply, &'static str),
-    //    UnknownMode(         NumReply, &'static str),
-    //    InviteOnlyChan(      NumReply, &'static str),
-    //    BannedFromChan(      NumReply, &'static str),
-    //    BadChannelKey(       NumReply, &'static str),
-    //    NoPrivileges(        NumReply, &'static str),
+    KeySet(String),
+    ChannelIsFull(String),
+    UnknownMode(char, String),
+    InviteOnlyChan(String),
+    BannedFromChan(String),
+    BanListFull(String, char),
+    BadChannelKey(String),
+    NoPrivileges,
     ChanOPrivsNeeded(String),
+    NoChanModes(String),
+    NeedReggedNick(String),
+    OperOnlyChan(String),
+    TooManyKnock(String, String),
+    ChanOpen(String),
+    KnockOnChan(String),
+    SaslFail,
     //    CantKillServer(      NumReply, &'static str),
     //    NoOperHost(          NumReply, &'static str),
-    //    UModeUnknownFlag(    NumReply, &'static str),
-    //    UsersDontMatch(      NumReply, &'static str),
+    UModeUnknownFlag,
+    UsersDontMatch,
     //BadChanMask(String)
     InvalidCommand(String),
     InvalidHost(String),
@@ -0,0 +1,182 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::irc::chan::{ChanTopic, Channel};
+use crate::irc::rfc_defs as rfc;
+
+/* the ELIST token: channel masks (M) and negated ones (N), user counts
+ * (U), creation time (C) and topic age (T) */
+pub const ELIST: &str = "CMNTU";
+
+/* what LIST's one parameter boils down to - it's a comma separated mix
+ * of channel masks and conditions, and a channel has to pass all of the
+ * conditions and match at least one of the masks, if there are any */
+#[derive(Debug, Default, Clone)]
+pub struct ListFilter {
+    masks: Vec<String>,
+    not_masks: Vec<String>,
+    more_users: Option<usize>,
+    fewer_users: Option<usize>,
+    created_after: Option<i64>,
+    created_before: Option<i64>,
+    topic_after: Option<i64>,
+    topic_before: Option<i64>,
+}
+
+/* "C<n" means created less than n minutes ago, so later than now - n
+ * minutes, and the same goes for T and the topic - a number too big to
+ * turn into seconds is as much use as one that doesn't parse */
+fn minutes_ago(now: i64, minutes: &str) -> Option<i64> {
+    minutes.parse::<i64>().ok()
+        .and_then(|minutes| minutes.checked_mul(60))
+        .and_then(|secs| now.checked_sub(secs))
+}
+
+/* '!' starts safe channel names as well as negating a mask, so it only
+ * negates when what follows is a channel mask in its own right - !#foo*
+ * and !!ABCDEfoo are negated, but !ABCDEfoo and !* are safe channels */
+fn is_chan_mask(mask: &str) -> bool {
+    mask.starts_with(|c| rfc::CHANTYPES.contains(c))
+}
+
+impl ListFilter {
+    /* conditions we can't make sense of are left out rather than
+     * treated as masks, nobody's channel is called "C<x" */
+    pub fn parse(param: &str, now: i64) -> ListFilter {
+        let mut filter = ListFilter::default();
+        for item in param.split(',').filter(|item| !item.is_empty()) {
+            if let Some(n) = item.strip_prefix('>') {
+                filter.more_users = n.parse().ok();
+            } else if let Some(n) = item.strip_prefix('<') {
+                filter.fewer_users = n.parse().ok();
+            } else if let Some(n) = item.strip_prefix("C<") {
+                filter.created_after = minutes_ago(now, n);
+            } else if let Some(n) = item.strip_prefix("C>") {
+                filter.created_before = minutes_ago(now, n);
+            } else if let Some(n) = item.strip_prefix("T<") {
+                filter.topic_after = minutes_ago(now, n);
+            } else if let Some(n) = item.strip_prefix("T>") {
+                filter.topic_before = minutes_ago(now, n);
+            } else if let Some(mask) = item.strip_prefix('!').filter(|mask| is_chan_mask(mask)) {
+                filter.not_masks.push(mask.to_string());
+            } else {
+                filter.masks.push(item.to_string());
+            }
+        }
+        filter
+    }
+
+    /* LIST #a,#b only needs those channels looked up, rather than every
+     * channel on the network checked against them */
+    pub fn exact_names(&self) -> Option<&[String]> {
+        let plain = |mask: &String| !mask.contains('*') && !mask.contains('?');
+        let only_names = self.not_masks.is_empty()
+            && self.more_users.is_none()
+            && self.fewer_users.is_none()
+            && self.created_after.is_none()
+            && self.created_before.is_none()
+            && self.topic_after.is_none()
+            && self.topic_before.is_none();
+        if only_names && !self.masks.is_empty() && self.masks.iter().all(plain) {
+            Some(&self.masks)
+        } else {
+            None
+        }
+    }
+
+    /* a channel without a topic never passes a T condition */
+    pub fn matches(&self, chan: &Channel, topic: &Option<ChanTopic>) -> bool {
+        let name = chan.get_name();
+        let n_users = chan.get_n_users();
+        let created = chan.get_created();
+        let topic_time = topic.as_ref().map(|topic| topic.timestamp);
+        (self.masks.is_empty() || self.masks.iter().any(|mask| rfc::mask_match(mask, &name)))
+            && !self.not_masks.iter().any(|mask| rfc::mask_match(mask, &name))
+            && self.more_users.is_none_or(|n| n_users > n)
+            && self.fewer_users.is_none_or(|n| n_users < n)
+            && self.created_after.is_none_or(|time| created > time)
+            && self.created_before.is_none_or(|time| created < time)
+            && self.topic_after.is_none_or(|time| topic_time.is_some_and(|topic_time| topic_time > time))
+            && self.topic_before.is_none_or(|time| topic_time.is_some_and(|topic_time| topic_time < time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_000_000;
+
+    #[test]
+    fn masks_and_user_counts() {
+        let filter = ListFilter::parse("#a*,>5,<20,#b", NOW);
+        assert_eq!(filter.masks, vec!["#a*", "#b"]);
+        assert_eq!(filter.more_users, Some(5));
+        assert_eq!(filter.fewer_users, Some(20));
+        assert!(filter.not_masks.is_empty());
+    }
+
+    #[test]
+    fn times_in_minutes() {
+        let filter = ListFilter::parse("C<10,C>60,T<1,T>2", NOW);
+        assert_eq!(filter.created_after, Some(NOW - 600));
+        assert_eq!(filter.created_before, Some(NOW - 3600));
+        assert_eq!(filter.topic_after, Some(NOW - 60));
+        assert_eq!(filter.topic_before, Some(NOW - 120));
+        assert!(filter.masks.is_empty());
+    }
+
+    #[test]
+    fn bad_conditions_dropped() {
+        let filter = ListFilter::parse(">x,<,C<abc,T>999999999999999999,C<9223372036854775807", NOW);
+        assert_eq!(filter.more_users, None);
+        assert_eq!(filter.fewer_users, None);
+        assert_eq!(filter.created_after, None);
+        assert_eq!(filter.topic_before, None);
+        assert!(filter.masks.is_empty());
+    }
+
+    #[test]
+    fn negated_masks() {
+        let filter = ListFilter::parse("!#secret*,!&local,!+x,!!ABCDEsafe", NOW);
+        assert_eq!(filter.not_masks, vec!["#secret*", "&local", "+x", "!ABCDEsafe"]);
+        assert!(filter.masks.is_empty());
+    }
+
+    #[test]
+    fn safe_channels_not_negated() {
+        let filter = ListFilter::parse("!ABCDEsafe,!*", NOW);
+        assert_eq!(filter.masks, vec!["!ABCDEsafe", "!*"]);
+        assert!(filter.not_masks.is_empty());
+    }
+
+    #[test]
+    fn empty_items_skipped() {
+        let filter = ListFilter::parse(",,#a,,", NOW);
+        assert_eq!(filter.masks, vec!["#a"]);
+    }
+
+    #[test]
+    fn exact_names_only_for_plain_names() {
+        let names = ["#a".to_string(), "!ABCDEsafe".to_string()];
+        assert_eq!(ListFilter::parse("#a,!ABCDEsafe", NOW).exact_names(), Some(&names[..]));
+        assert_eq!(ListFilter::parse("#a*", NOW).exact_names(), None);
+        assert_eq!(ListFilter::parse("#a,#b?", NOW).exact_names(), None);
+        assert_eq!(ListFilter::parse("#a,>2", NOW).exact_names(), None);
+        assert_eq!(ListFilter::parse("#a,!#b", NOW).exact_names(), None);
+        assert_eq!(ListFilter::parse("", NOW).exact_names(), None);
+    }
+}
@@ -0,0 +1,412 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::client::ClientReplies;
+use crate::irc::ban;
+use crate::irc::chan::{ChanFlags, Channel};
+use crate::irc::error::Error as ircError;
+use crate::irc::reply::Reply as ircReply;
+use crate::irc::User;
+
+/* how many parameter-taking modes we'll act on from one MODE command,
+ * anything past that is quietly dropped */
+pub const MAX_PARAM_MODES: usize = 4;
+
+/* prefix modes take a nick and change what that user may do, list
+ * modes take a mask (without one they're a query), Param modes take a
+ * parameter both ways, SetParam only when being set, and flags are
+ * simply on or off and never take one */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ModeType {
+    Prefix(ChanFlags),
+    List,
+    Param,
+    SetParam,
+    Flag,
+}
+
+pub fn chan_mode_type(mode: char) -> Option<ModeType> {
+    match mode {
+        'q' => Some(ModeType::Prefix(ChanFlags::Owner)),
+        'a' => Some(ModeType::Prefix(ChanFlags::Admin)),
+        'o' => Some(ModeType::Prefix(ChanFlags::Op)),
+        'h' => Some(ModeType::Prefix(ChanFlags::HalfOp)),
+        'v' => Some(ModeType::Prefix(ChanFlags::Voice)),
+        'b' | 'e' | 'I' => Some(ModeType::List),
+        'k' => Some(ModeType::Param),
+        'l' => Some(ModeType::SetParam),
+        'A' | 'i' | 'm' | 'n' | 'O' | 'p' | 'r' | 'R' | 's' | 't' => Some(ModeType::Flag),
+        _ => None,
+    }
+}
+
+/* ISUPPORT CHANMODES, the modes we know sorted into the four types
+ * clients care about - prefix modes go in PREFIX instead */
+pub fn chanmodes_token(modes: &str) -> String {
+    let mut types = [String::new(), String::new(), String::new(), String::new()];
+    for mode in modes.chars() {
+        let slot = match chan_mode_type(mode) {
+            Some(ModeType::List) => 0,
+            Some(ModeType::Param) => 1,
+            Some(ModeType::SetParam) => 2,
+            Some(ModeType::Flag) => 3,
+            Some(ModeType::Prefix(_)) | None => continue,
+        };
+        types[slot].push(mode);
+    }
+    format!("CHANMODES={}", types.join(","))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModeChange {
+    pub set: bool,
+    pub mode: char,
+    pub param: Option<String>,
+}
+
+/* walk a mode string like +ov-v handing out the parameters in order to
+ * the modes that want them, a mode that wants one but finds none left
+ * is skipped, as is anything past MAX_PARAM_MODES */
+pub fn parse_chan_modes(chan: &str, modestring: &str, params: &[String]) -> (Vec<ModeChange>, ClientReplies) {
+    let mut changes = Vec::new();
+    let mut replies = Vec::new();
+    let mut params = params.iter();
+    let mut n_params = 0;
+    let mut set = true;
+    for mode in modestring.chars() {
+        match mode {
+            '+' => set = true,
+            '-' => set = false,
+            _ => match chan_mode_type(mode) {
+                /* -k with no key is fine, and whatever key is given is ignored */
+                Some(ModeType::Param) if !set => {
+                    let _key = params.next();
+                    changes.push(ModeChange { set, mode, param: None });
+                },
+                Some(ModeType::SetParam) if !set => changes.push(ModeChange { set, mode, param: None }),
+                Some(ModeType::Flag) => changes.push(ModeChange { set, mode, param: None }),
+                Some(mode_type) => {
+                    if n_params >= MAX_PARAM_MODES {
+                        continue;
+                    }
+                    match params.next() {
+                        Some(param) => {
+                            n_params += 1;
+                            changes.push(ModeChange { set, mode, param: Some(param.clone()) });
+                        },
+                        None if mode_type == ModeType::List => changes.push(ModeChange { set, mode, param: None }),
+                        None => (),
+                    }
+                },
+                None => replies.push(Err(ircError::UnknownMode(mode, chan.to_string()))),
+            },
+        }
+    }
+    (changes, replies)
+}
+
+/* halfops look after the lists and can voice people (prefix modes
+ * are sorted out by may_set_rank()), a safe channel's +r is up to its
+ * creator, and everything else is for ops */
+fn required_rank(mode: char, mode_type: ModeType) -> ChanFlags {
+    match (mode, mode_type) {
+        (_, ModeType::Prefix(_)) | (_, ModeType::List) => ChanFlags::HalfOp,
+        ('r', _) => ChanFlags::Owner,
+        _ => ChanFlags::Op,
+    }
+}
+
+/* you need to be able to hand out a rank to touch it at all, and can't
+ * take anything from somebody who outranks you, though anyone may
+ * step down themselves */
+fn may_set_rank(chan: &Channel, source: &User, rank: Option<ChanFlags>, flag: ChanFlags, change: &ModeChange) -> bool {
+    let nick = change.param.as_deref().unwrap_or_default();
+    if !change.set && chan.get_member(nick).is_some_and(|member| member.id == source.id) {
+        return true;
+    }
+    match rank {
+        Some(rank) => rank.can_grant(flag) && (change.set || chan.get_rank(nick) <= Some(rank)),
+        None => false,
+    }
+}
+
+/* apply what parse_chan_modes() came up with, returning the changes
+ * that actually made a difference (those are what get broadcast) along
+ * with any errors for the user who sent them */
+pub fn apply_chan_modes(chan: &Channel, source: &User, changes: Vec<ModeChange>) -> (Vec<ModeChange>, ClientReplies) {
+    let mut applied = Vec::new();
+    let mut replies = Vec::new();
+    let rank = chan.get_rank(&source.get_nick());
+    let mut denied = false;
+
+    for mut change in changes {
+        let mode_type = match chan_mode_type(change.mode) {
+            Some(mode_type) => mode_type,
+            None => continue,
+        };
+        if !chan.supports_mode(change.mode) {
+            replies.push(Err(ircError::UnknownMode(change.mode, chan.get_name())));
+            continue;
+        }
+        /* a list mode on its own is just asking to see the list */
+        if let (ModeType::List, None) = (mode_type, &change.param) {
+            if change.mode == 'b' || rank >= Some(ChanFlags::HalfOp) {
+                replies.append(&mut list_replies(chan, change.mode));
+            } else {
+                denied = true;
+            }
+            continue;
+        }
+        let allowed = match mode_type {
+            ModeType::Prefix(flag) => may_set_rank(chan, source, rank, flag, &change),
+            _ => rank >= Some(required_rank(change.mode, mode_type)),
+        };
+        if !allowed {
+            denied = true;
+            continue;
+        }
+        match mode_type {
+            ModeType::Prefix(flag) => {
+                let nick = change.param.clone().unwrap_or_default();
+                match chan.set_flag(&nick, flag, change.set) {
+                    Ok(Some(real_nick)) => {
+                        change.param = Some(real_nick);
+                        applied.push(change);
+                    },
+                    Ok(None) => (), /* they already had it, or didn't */
+                    Err(err) => replies.push(Err(err)),
+                }
+            },
+            /* an oper-only channel is for opers to make */
+            ModeType::Flag if change.mode == 'O' && !source.is_oper() => {
+                replies.push(Err(ircError::NoPrivileges));
+            },
+            ModeType::Flag if chan.set_mode(change.mode, change.set) => applied.push(change),
+            ModeType::Param => match apply_key(chan, &change) {
+                Ok(Some(param)) => {
+                    change.param = Some(param);
+                    applied.push(change);
+                },
+                Ok(None) => (),
+                Err(err) => replies.push(Err(err)),
+            },
+            ModeType::SetParam => {
+                if let Some(param) = apply_limit(chan, &change) {
+                    change.param = param;
+                    applied.push(change);
+                }
+            },
+            ModeType::List => match apply_mask(chan, source, &change) {
+                Ok(Some(mask)) => {
+                    change.param = Some(mask);
+                    applied.push(change);
+                },
+                Ok(None) => (),
+                Err(err) => replies.push(Err(err)),
+            },
+            ModeType::Flag => (),
+        }
+    }
+    if denied {
+        replies.push(Err(ircError::ChanOPrivsNeeded(chan.get_name())));
+    }
+    (applied, replies)
+}
+
+fn list_replies(chan: &Channel, mode: char) -> ClientReplies {
+    let name = chan.get_name();
+    let mut replies = chan.get_mask_list(mode)
+        .into_iter()
+        .map(|entry| Ok(ircReply::MaskList(mode, name.clone(), entry.mask, entry.set_by, entry.timestamp)))
+        .collect::<Vec<_>>();
+    replies.push(Ok(ircReply::EndofMaskList(mode, name)));
+    replies
+}
+
+/* masks are stored the way they'll be matched, so "-b nick" finds the
+ * "nick!*@*" that "+b nick" put there - extbans we don't understand are
+ * dropped, but -b takes whatever it's given in case it's on the list */
+fn apply_mask(chan: &Channel, source: &User, change: &ModeChange) -> Result<Option<String>, ircError> {
+    let param = change.param.as_deref().unwrap_or_default();
+    let mask = match ban::normalize_mask(param) {
+        Some(mask) => mask,
+        None if change.set => return Ok(None),
+        None => param.to_string(),
+    };
+    if change.set {
+        Ok(if chan.add_mask(change.mode, &mask, &source.get_prefix())? { Some(mask) } else { None })
+    } else {
+        Ok(chan.rm_mask(change.mode, &mask))
+    }
+}
+
+/* +k replaces nothing, take the old key off first - what comes back
+ * is the parameter to broadcast, -k shows everyone a * */
+fn apply_key(chan: &Channel, change: &ModeChange) -> Result<Option<String>, ircError> {
+    match &change.param {
+        Some(key) if change.set => {
+            if key.is_empty() || key.contains(',') || key.contains(':') {
+                return Ok(None);
+            }
+            if chan.get_key().is_some() {
+                return Err(ircError::KeySet(chan.get_name()));
+            }
+            chan.set_key(Some(key.to_string()));
+            Ok(Some(key.to_string()))
+        },
+        _ if chan.set_key(None) => Ok(Some("*".to_string())),
+        _ => Ok(None),
+    }
+}
+
+/* anything that isn't a positive number just doesn't get set, which
+ * is the traditional way of telling people off for +l 0 */
+fn apply_limit(chan: &Channel, change: &ModeChange) -> Option<Option<String>> {
+    if !change.set {
+        return if chan.set_limit(None) { Some(None) } else { None };
+    }
+    match change.param.as_deref().map(str::parse::<usize>) {
+        Some(Ok(limit)) if limit > 0 && chan.set_limit(Some(limit)) => Some(Some(limit.to_string())),
+        _ => None,
+    }
+}
+
+/* turn a list of changes back into "+ov-v nick1 nick2 nick3" */
+pub fn format_changes(changes: &[ModeChange]) -> String {
+    let mut modes = String::new();
+    let mut params = Vec::new();
+    let mut current = None;
+    for change in changes.iter() {
+        if current != Some(change.set) {
+            modes.push(if change.set { '+' } else { '-' });
+            current = Some(change.set);
+        }
+        modes.push(change.mode);
+        if let Some(param) = &change.param {
+            params.push(param.clone());
+        }
+    }
+    if params.is_empty() {
+        modes
+    } else {
+        format!("{} {}", modes, params.join(" "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(set: bool, mode: char, param: Option<&str>) -> ModeChange {
+        ModeChange { set, mode, param: param.map(str::to_string) }
+    }
+
+    fn params(list: &[&str]) -> Vec<String> {
+        list.iter().map(|param| param.to_string()).collect()
+    }
+
+    #[test]
+    fn parse_hands_out_params_in_order() {
+        let (changes, replies) = parse_chan_modes("#test", "+ov-v", &params(&["alice", "bob", "carol"]));
+        assert!(replies.is_empty());
+        assert_eq!(changes, vec![
+            change(true, 'o', Some("alice")),
+            change(true, 'v', Some("bob")),
+            change(false, 'v', Some("carol")),
+        ]);
+    }
+
+    #[test]
+    fn parse_flags_take_no_params() {
+        let (changes, _replies) = parse_chan_modes("#test", "+nt-m+l", &params(&["10"]));
+        assert_eq!(changes, vec![
+            change(true, 'n', None),
+            change(true, 't', None),
+            change(false, 'm', None),
+            change(true, 'l', Some("10")),
+        ]);
+    }
+
+    #[test]
+    fn parse_unset_key_and_limit() {
+        /* -k eats the key it's given but doesn't keep it, -l takes none */
+        let (changes, _replies) = parse_chan_modes("#test", "-kl+v", &params(&["secret", "alice"]));
+        assert_eq!(changes, vec![
+            change(false, 'k', None),
+            change(false, 'l', None),
+            change(true, 'v', Some("alice")),
+        ]);
+    }
+
+    #[test]
+    fn parse_list_without_mask_is_query() {
+        let (changes, _replies) = parse_chan_modes("#test", "b", &[]);
+        assert_eq!(changes, vec![change(true, 'b', None)]);
+    }
+
+    #[test]
+    fn parse_skips_modes_missing_params() {
+        let (changes, _replies) = parse_chan_modes("#test", "+ok", &params(&["alice"]));
+        assert_eq!(changes, vec![change(true, 'o', Some("alice"))]);
+    }
+
+    #[test]
+    fn parse_stops_at_max_param_modes() {
+        let (changes, _replies) = parse_chan_modes("#test", "+vvvvv", &params(&["a", "b", "c", "d", "e"]));
+        assert_eq!(changes.len(), MAX_PARAM_MODES);
+        assert_eq!(changes.last(), Some(&change(true, 'v', Some("d"))));
+    }
+
+    #[test]
+    fn parse_reports_unknown_modes() {
+        let (changes, replies) = parse_chan_modes("#test", "+xn", &[]);
+        assert_eq!(changes, vec![change(true, 'n', None)]);
+        assert_eq!(replies.len(), 1);
+        match &replies[0] {
+            Err(err) => assert_eq!(err.to_string(), "472 x :is unknown mode char to me for #test"),
+            Ok(_reply) => panic!("expected an error"),
+        }
+    }
+
+    #[test]
+    fn format_groups_signs() {
+        let changes = vec![
+            change(true, 'o', Some("alice")),
+            change(true, 'v', Some("bob")),
+            change(false, 'v', Some("carol")),
+            change(false, 'm', None),
+            change(true, 't', None),
+        ];
+        assert_eq!(format_changes(&changes), "+ov-vm+t alice bob carol");
+    }
+
+    #[test]
+    fn format_without_params() {
+        assert_eq!(format_changes(&[change(false, 'n', None)]), "-n");
+        assert_eq!(format_changes(&[]), "");
+    }
+
+    #[test]
+    fn format_round_trips_parse() {
+        let (changes, _replies) = parse_chan_modes("#test", "+o-v+k", &params(&["alice", "bob", "key"]));
+        assert_eq!(format_changes(&changes), "+o-v+k alice bob key");
+    }
+
+    #[test]
+    fn chanmodes_sorted_by_type() {
+        assert_eq!(chanmodes_token("bklimov"), "CHANMODES=b,k,l,im");
+    }
+}
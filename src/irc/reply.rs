@@ -49,18 +49,66 @@ use crate::irc::chan::ChanTopic;
 
 pub enum Reply {
     None,
-    Welcome(String, String, String),
+    Welcome(String, String, String, String),
     YourHost(String, String),
     Created(String),
     MyInfo(String, String, String, String),
+    ISupport(Vec<String>),
+    LuserClient(usize, usize),
+    LuserOp(usize),
+    LuserUnknown(usize),
+    LuserChannels(usize),
+    LuserMe(usize),
+    LocalUsers(usize, usize),
+    GlobalUsers(usize, usize),
+    StatsLinkInfo(String, usize, u64, u64, u64, u64, i64),
+    StatsCommands(String, u64, u64),
+    EndofStats(char),
+    StatsUptime(i64),
+    StatsOLine(String),
+    AdminMe(String),
+    AdminLoc1(String),
+    AdminLoc2(String),
+    AdminEmail(String),
+    Version(String, String, String),
+    Time(String, String),
+    Info(String),
+    EndofInfo,
+    WhoisUser(String, String, String, String),
+    WhoisServer(String, String, String),
+    WhoisOperator(String),
+    WhoisIdle(String, i64, i64),
+    EndofWhois(String),
+    WhoisChannels(String, Vec<String>),
+    WhoisAccount(String, String),
+    WhoisSecure(String),
+    WhoReply(String, String, String, String, String, String, String),
+    WhowasUser(String, String, String, String),
+    EndofWhowas(String),
+    WhoSpcRpl(Vec<String>),
+    EndofWho(String),
+    MotdStart(String),
+    Motd(String),
+    EndofMotd,
     NoTopic(String),
     Topic(String, String),
     TopicSetBy(String, String, i64),
-    NameReply(String, Vec<String>),
+    NameReply(char, String, Vec<String>),
     EndofNames(String),
     ListStart,
     ListReply(String, usize, Option<ChanTopic>),
     EndofList,
+    UModeIs(String),
+    MaskList(char, String, String, String, i64),
+    EndofMaskList(char, String),
+    ChannelModeIs(String, String),
+    CreationTime(String, i64),
+    Inviting(String, String),
+    KnockDelivered(String),
+    YoureOper,
+    Rehashing(String),
+    LoggedIn(String, String),
+    Notice(String),
 }
 
 type Code = u16;
@@ -70,36 +118,136 @@ impl Reply {
     /* map enums to numberic reply codes */
     fn numeric(&self) -> Code {
         match self {
-            Reply::Welcome(_n, _u, _h) => 001,
-            Reply::YourHost(_s,_v) => 002,
-            Reply::Created(_t) => 003,
-            Reply::MyInfo(_s, _v, _um, _cm) => 004,
+            Reply::Welcome(_net, _n, _u, _h) => 1,
+            Reply::YourHost(_s,_v) => 2,
+            Reply::Created(_t) => 3,
+            Reply::MyInfo(_s, _v, _um, _cm) => 4,
+            Reply::ISupport(_tokens) => 5,
+            Reply::LuserClient(_vis, _inv) => 251,
+            Reply::LuserOp(_n) => 252,
+            Reply::LuserUnknown(_n) => 253,
+            Reply::LuserChannels(_n) => 254,
+            Reply::LuserMe(_n) => 255,
+            Reply::LocalUsers(_cur, _max) => 265,
+            Reply::GlobalUsers(_cur, _max) => 266,
+            Reply::Motd(_line) => 372,
+            Reply::MotdStart(_serv) => 375,
+            Reply::EndofMotd => 376,
+            Reply::StatsLinkInfo(_name, _sq, _sm, _skb, _rm, _rkb, _open) => 211,
+            Reply::StatsCommands(_cmd, _count, _bytes) => 212,
+            Reply::EndofStats(_letter) => 219,
+            Reply::UModeIs(_modes) => 221,
+            Reply::StatsUptime(_secs) => 242,
+            Reply::StatsOLine(_name) => 243,
+            Reply::AdminMe(_serv) => 256,
+            Reply::AdminLoc1(_text) => 257,
+            Reply::AdminLoc2(_text) => 258,
+            Reply::AdminEmail(_text) => 259,
+            Reply::WhoisUser(_nick, _user, _host, _real) => 311,
+            Reply::WhoisServer(_nick, _serv, _info) => 312,
+            Reply::WhoisOperator(_nick) => 313,
+            Reply::EndofWho(_mask) => 315,
+            Reply::WhoisIdle(_nick, _idle, _signon) => 317,
+            Reply::EndofWhois(_nick) => 318,
+            Reply::WhoisChannels(_nick, _chans) => 319,
+            Reply::WhowasUser(_nick, _user, _host, _real) => 314,
+            Reply::WhoisAccount(_nick, _acct) => 330,
+            Reply::EndofWhowas(_nick) => 369,
+            Reply::Version(_ver, _serv, _comment) => 351,
+            Reply::WhoReply(_ch, _user, _host, _serv, _nick, _flags, _real) => 352,
+            Reply::WhoSpcRpl(_fields) => 354,
+            Reply::WhoisSecure(_nick) => 671,
+            Reply::Info(_text) => 371,
+            Reply::EndofInfo => 374,
+            Reply::Time(_serv, _time) => 391,
             Reply::None => 300,
             Reply::ListStart => 321,
             Reply::ListReply(_ch, _nu, _top) => 322,
             Reply::EndofList => 323,
+            Reply::MaskList('I', _ch, _mask, _by, _stamp) => 346,
+            Reply::EndofMaskList('I', _ch) => 347,
+            Reply::MaskList('e', _ch, _mask, _by, _stamp) => 348,
+            Reply::EndofMaskList('e', _ch) => 349,
+            Reply::MaskList(_mode, _ch, _mask, _by, _stamp) => 367,
+            Reply::EndofMaskList(_mode, _ch) => 368,
+            Reply::ChannelModeIs(_ch, _modes) => 324,
+            Reply::CreationTime(_ch, _stamp) => 329,
+            Reply::Inviting(_nick, _ch) => 341,
+            Reply::KnockDelivered(_ch) => 711,
+            Reply::YoureOper => 381,
+            Reply::Rehashing(_file) => 382,
+            Reply::LoggedIn(_mask, _acct) => 900,
+            Reply::Notice(_text) => 0, /* not a numeric, see reply_code() */
             Reply::NoTopic(_ch) => 331,
             Reply::Topic(_ch, _top) => 332,
             Reply::TopicSetBy(_ch, _umask, _stamp) => 333,
-            Reply::NameReply(_ch, _ns) => 353,
+            Reply::NameReply(_sym, _ch, _ns) => 353,
             Reply::EndofNames(_ch) => 366
         }
     }
 
     /* convert reply codes to strings */
     fn reply_code(&self) -> CodeStr {
-        format!("{:03}", self.numeric())
+        match self {
+            Reply::Notice(_text) => "NOTICE".to_string(),
+            _ => format!("{:03}", self.numeric()),
+        }
     }
 
     /* the body is everything in the reply after :<server> <Code> <recipient> */
     fn body(&self) -> Option<String> {
         match self {
             Reply::None => None,
-            Reply::Welcome(nick, user, host) => Some(format!(":Welcome to Rusty IRC Network {}!{}@{}", nick, user, host)),
+            Reply::Welcome(network, nick, user, host) => Some(format!(":Welcome to the {} IRC Network {}!{}@{}", network, nick, user, host)),
             Reply::YourHost(serv, ver) => Some(format!(":Your host is {}, running version {}", serv, ver)),
             Reply::Created(time) => Some(format!(":This server was created {}", time)),
-            Reply::MyInfo(serv, ver, umodes, chanmodes) => Some(format!(":{} {} {} {}", serv, ver, umodes, chanmodes)),
-            Reply::ListStart => Some(format!("Channel Users :Topic")),
+            Reply::MyInfo(serv, ver, umodes, chanmodes) => Some(format!("{} {} {} {}", serv, ver, umodes, chanmodes)),
+            Reply::ISupport(tokens) => Some(format!("{} :are supported by this server", tokens.join(" "))),
+            /* no links yet, so we're always the one server */
+            Reply::LuserClient(visible, invisible) => Some(format!(":There are {} users and {} invisible on 1 servers", visible, invisible)),
+            Reply::LuserOp(n) => Some(format!("{} :operator(s) online", n)),
+            Reply::LuserUnknown(n) => Some(format!("{} :unknown connection(s)", n)),
+            Reply::LuserChannels(n) => Some(format!("{} :channels formed", n)),
+            Reply::LuserMe(n) => Some(format!(":I have {} clients and 0 servers", n)),
+            Reply::LocalUsers(cur, max) => Some(format!("{} {} :Current local users {}, max {}", cur, max, cur, max)),
+            Reply::GlobalUsers(cur, max) => Some(format!("{} {} :Current global users {}, max {}", cur, max, cur, max)),
+            Reply::StatsLinkInfo(name, sendq, sent_msgs, sent_kb, recv_msgs, recv_kb, open) => {
+                Some(format!("{} {} {} {} {} {} {}", name, sendq, sent_msgs, sent_kb, recv_msgs, recv_kb, open))
+            },
+            Reply::StatsCommands(cmd, count, bytes) => Some(format!("{} {} {} 0", cmd, count, bytes)),
+            Reply::EndofStats(letter) => Some(format!("{} :End of /STATS report", letter)),
+            Reply::StatsUptime(secs) => {
+                Some(format!(":Server Up {} days {}:{:02}:{:02}", secs / 86400, secs % 86400 / 3600, secs % 3600 / 60, secs % 60))
+            },
+            Reply::StatsOLine(name) => Some(format!("O * * {}", name)),
+            Reply::AdminMe(serv) => Some(format!("{} :Administrative info", serv)),
+            Reply::AdminLoc1(text) => Some(format!(":{}", text)),
+            Reply::AdminLoc2(text) => Some(format!(":{}", text)),
+            Reply::AdminEmail(text) => Some(format!(":{}", text)),
+            Reply::Version(ver, serv, comment) => Some(format!("{}. {} :{}", ver, serv, comment)),
+            Reply::Time(serv, time) => Some(format!("{} :{}", serv, time)),
+            Reply::Info(text) => Some(format!(":{}", text)),
+            Reply::EndofInfo => Some(":End of /INFO list".to_string()),
+            Reply::WhoisUser(nick, user, host, real) => Some(format!("{} {} {} * :{}", nick, user, host, real)),
+            Reply::WhoisServer(nick, serv, info) => Some(format!("{} {} :{}", nick, serv, info)),
+            Reply::WhoisOperator(nick) => Some(format!("{} :is an IRC operator", nick)),
+            Reply::WhoisIdle(nick, idle, signon) => Some(format!("{} {} {} :seconds idle, signon time", nick, idle, signon)),
+            Reply::EndofWhois(nick) => Some(format!("{} :End of /WHOIS list", nick)),
+            Reply::WhoisChannels(nick, chans) => Some(format!("{} :{}", nick, chans.join(" "))),
+            Reply::WhoisAccount(nick, account) => Some(format!("{} {} :is logged in as", nick, account)),
+            Reply::WhoisSecure(nick) => Some(format!("{} :is using a secure connection", nick)),
+            /* no links, so the hopcount's always 0 */
+            Reply::WhoReply(chan, user, host, serv, nick, flags, real) => {
+                Some(format!("{} {} {} {} {} {} :0 {}", chan, user, host, serv, nick, flags, real))
+            },
+            Reply::WhowasUser(nick, user, host, real) => Some(format!("{} {} {} * :{}", nick, user, host, real)),
+            Reply::EndofWhowas(nick) => Some(format!("{} :End of WHOWAS", nick)),
+            Reply::WhoSpcRpl(fields) => Some(fields.join(" ")),
+            Reply::EndofWho(mask) => Some(format!("{} :End of /WHO list", mask)),
+            Reply::MotdStart(serv) => Some(format!(":- {} Message of the day - ", serv)),
+            Reply::Motd(line) => Some(format!(":- {}", line)),
+            Reply::EndofMotd => Some(":End of /MOTD command.".to_string()),
+            Reply::ListStart => Some("Channel Users :Topic".to_string()),
             Reply::ListReply(chan, n_users, topic_opt) => {
                 if let Some(topic) = topic_opt {
                     Some(format!("{} {} :{}", chan, n_users, topic.text))
@@ -107,11 +255,24 @@ impl Reply {
                     Some(format!("{} {}", chan, n_users))
                 }
             },
-            Reply::EndofList => Some(format!(":End of /LIST")),
+            Reply::EndofList => Some(":End of /LIST".to_string()),
+            Reply::UModeIs(modes) => Some(modes.to_string()),
+            Reply::MaskList(_mode, chan, mask, set_by, timestamp) => Some(format!("{} {} {} {}", chan, mask, set_by, timestamp)),
+            Reply::EndofMaskList('I', chan) => Some(format!("{} :End of Channel Invite List", chan)),
+            Reply::EndofMaskList('e', chan) => Some(format!("{} :End of Channel Exception List", chan)),
+            Reply::EndofMaskList(_mode, chan) => Some(format!("{} :End of Channel Ban List", chan)),
+            Reply::ChannelModeIs(chan, modes) => Some(format!("{} {}", chan, modes)),
+            Reply::CreationTime(chan, timestamp) => Some(format!("{} {}", chan, timestamp)),
+            Reply::Inviting(nick, chan) => Some(format!("{} {}", nick, chan)),
+            Reply::KnockDelivered(chan) => Some(format!("{} :Your KNOCK has been delivered", chan)),
+            Reply::YoureOper => Some(":You are now an IRC operator".to_string()),
+            Reply::Rehashing(file) => Some(format!("{} :Rehashing", file)),
+            Reply::LoggedIn(mask, account) => Some(format!("{} {} :You are now logged in as {}", mask, account, account)),
+            Reply::Notice(text) => Some(format!(":{}", text)),
             Reply::NoTopic(chan) => Some(format!("{} :No topic is set.", chan)),
             Reply::Topic(chan, topic_msg) => Some(format!("{} :{}", chan, topic_msg)),
             Reply::TopicSetBy(chan, usermask, timestamp) => Some(format!("{} {} {}", chan, usermask, timestamp)),
-            Reply::NameReply(chan, nicks) => Some(format!("{} :{}", chan, nicks.join(" "))),
+            Reply::NameReply(symbol, chan, nicks) => Some(format!("{} {} :{}", symbol, chan, nicks.join(" "))),
             Reply::EndofNames(chan) => Some(format!("{} :End of /NAMES list", chan)),
         }
     }
@@ -179,26 +340,9 @@ fn rfind_space_index (bytes: &[u8], mut index: usize) -> Option<usize> {
 
 impl fmt::Display for Reply {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            Reply::None => write!(f, "300"),
-            Reply::Welcome(nick, user, host) => write!(f, "001 :Welcome to Rusty IRC Network {}!{}@{}", nick, user, host),
-            Reply::YourHost(serv, ver) => write!(f, "002 :Your host is {}, running version {}", serv, ver),
-            Reply::Created(time) => write!(f, "003 :This server was created {}", time),
-            Reply::MyInfo(serv, ver, umodes, chanmodes) => write!(f, "004 :{} {} {} {}", serv, ver, umodes, chanmodes),
-            Reply::ListStart => write!(f, "321 Chan Users :Topic"),
-            Reply::ListReply(chan, n_users, topic_opt) => {
-                if let Some(topic) = topic_opt {
-                    write!(f, "322 {} {} :{}", chan, n_users, topic.text)
-                } else {
-                    write!(f, "322 {} {}", chan, n_users)
-                }
-            },
-            Reply::EndofList => write!(f, "323 :End of /LIST"),
-            Reply::NoTopic(chan) => write!(f, "331 {} :No topic is set", chan),
-            Reply::Topic(chan, topic_msg) => write!(f, "332 {} :{}", chan, topic_msg),
-            Reply::TopicSetBy(chan, usermask, timestamp) => write!(f, "333 {} {} {}", chan, usermask, timestamp),
-            Reply::NameReply(chan, nicks) => write!(f, "353 {} :{}", chan, nicks.join(" ")),
-            Reply::EndofNames(chan) => write!(f, "366 {} :End of /NAMES list", chan),
+        match self.body() {
+            Some(body) => write!(f, "{} {}", self.reply_code(), body),
+            None => write!(f, "{}", self.reply_code()),
         }
     }
-}
\ No newline at end of file
+}
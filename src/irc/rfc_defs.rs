@@ -14,6 +14,8 @@
 *  You should have received a copy of the GNU Lesser General Public License
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
+use serde::Deserialize;
+
 pub const MAX_MSG_SIZE: usize = 512;
 pub const MAX_MSG_PARAMS: usize = 15; // including tailing, but not including COMMAND
 pub const LETTER: &str = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
@@ -23,6 +25,42 @@ pub const SPECIAL: &str = "[]\\`_^{|}";
 pub const DIGIT: &str = "0123456789";
 pub const HEXDIGIT: &str = "0123456789ABCDEF";
 
+// how nicks and channel names are compared, rfc1459 being the one
+// where {}|^ are the lower case forms of []\~ (strict leaves ~ and ^
+// alone), as the Scandinavian origins of IRC would have it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseMapping {
+    Ascii,
+    #[default]
+    Rfc1459,
+    StrictRfc1459,
+}
+
+impl CaseMapping {
+    // what ISUPPORT CASEMAPPING calls it
+    pub fn name(&self) -> &'static str {
+        match self {
+            CaseMapping::Ascii => "ascii",
+            CaseMapping::Rfc1459 => "rfc1459",
+            CaseMapping::StrictRfc1459 => "strict-rfc1459",
+        }
+    }
+
+    // the form names are keyed by, two names are the same if these match
+    pub fn fold(&self, name: &str) -> String {
+        name.chars()
+            .map(|c| match (self, c) {
+                (_, 'A'..='Z') => c.to_ascii_lowercase(),
+                (CaseMapping::Rfc1459, '[') | (CaseMapping::StrictRfc1459, '[') => '{',
+                (CaseMapping::Rfc1459, ']') | (CaseMapping::StrictRfc1459, ']') => '}',
+                (CaseMapping::Rfc1459, '\\') | (CaseMapping::StrictRfc1459, '\\') => '|',
+                (CaseMapping::Rfc1459, '~') => '^',
+                _ => c,
+            }).collect()
+    }
+}
+
 // user can have any character which is not in the set CONTROL, or an '@'
 pub const CONTROL: &str = "\0\r\n :";
 pub const NOT_USER: &str = "\0\r\n @";
@@ -87,13 +125,10 @@ pub fn valid_ipv6_addr(host_addr: &str) -> bool {
         true
     } else if toks.len() == 7 {
         for (i, item) in toks.iter().enumerate() {
-            if item.is_empty() {
-                return false;
-            } else if i < 5 && &item[..] != "0" {
-                return false;
-            } else if i == 5 && !(&item[..] == "0" || &item[..] == "FFFF") {
-                return false;
-            } else if i == 6 && !valid_ipv4_addr(item) {
+            if item.is_empty()
+                || (i < 5 && &item[..] != "0")
+                || (i == 5 && &item[..] != "0" && &item[..] != "FFFF")
+                || (i == 6 && !valid_ipv4_addr(item)) {
                 return false;
             }
         }
@@ -137,7 +172,7 @@ pub fn valid_shortname(shortname: &str) -> bool {
         let mut allowed = String::new();
         allowed.push_str(LETTER);
         allowed.push_str(DIGIT);
-        allowed.push_str("-");
+        allowed.push('-');
         matches_allowed(shortname, &allowed)
     } else {
         false
@@ -200,6 +235,22 @@ pub fn valid_channel(channame: &str) -> bool {
     true
 }
 
+// the channel name prefixes we know, for ISUPPORT CHANTYPES
+pub const CHANTYPES: &str = "#&!+";
+
+// a safe channel's ID is the time in base 36 (A-Z then 0-9), wrapping
+// around every 36^5 seconds or so, which RFC 2811 reckons is enough
+pub fn safe_channel_id(timestamp: i64) -> String {
+    let digits = format!("{}{}", UPPER, DIGIT).into_bytes();
+    let mut n = timestamp.rem_euclid(36_i64.pow(5));
+    let mut id = vec![b'A'; 5];
+    for place in id.iter_mut().rev() {
+        *place = digits[(n % 36) as usize];
+        n /= 36;
+    }
+    String::from_utf8(id).unwrap()
+}
+
 // rfc says this should be a 5-character string containing A-Z or digits
 pub fn valid_channelid(channelid: &str) -> bool {
     if channelid.len() == 5 {
@@ -212,15 +263,52 @@ pub fn valid_channelid(channelid: &str) -> bool {
     }
 }
 
+// cut a string down to at most max bytes without splitting a UTF-8
+// sequence, for the length limits the server config sets
+pub fn truncate(text: &str, max: usize) -> &str {
+    if text.len() <= max {
+        return text;
+    }
+    let mut end = max;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+// break text into pieces of at most max bytes, at the last space that
+// fits if there is one, for lines too long to send in one go - a
+// character wider than max gets a piece to itself rather than none
+pub fn wrap(text: &str, max: usize) -> Vec<&str> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while rest.len() > max && max > 0 {
+        let mut piece = truncate(rest, max);
+        if let Some(space) = piece.rfind(' ').filter(|space| *space > 0) {
+            piece = &piece[..space];
+        } else if piece.is_empty() {
+            let width = rest.chars().next().map_or(0, char::len_utf8);
+            piece = &rest[..width];
+        }
+        pieces.push(piece);
+        rest = rest[piece.len()..].strip_prefix(' ').unwrap_or(&rest[piece.len()..]);
+    }
+    if !rest.is_empty() || pieces.is_empty() {
+        pieces.push(rest);
+    }
+    pieces
+}
+
 // very permissive, can be anything except NUL, BELL, CR, LF, ",", " ", ":"
 pub fn valid_chanstring(chanstring: &str) -> bool {
     !matches_disallowed(chanstring, NOT_CHANSTRING)
 }
 
 // rfc states nick should be max 9 in length,
-// pretty sure I've seen far longer nicks on most IRC servers though
+// pretty sure I've seen far longer nicks on most IRC servers though,
+// so the length limit is left to the server config (limits.nick_len)
 pub fn valid_nick(nick: &str) -> bool {
-    if nick.len() > 9 || nick.is_empty() {
+    if nick.is_empty() {
         return false;
     }
 
@@ -239,6 +327,166 @@ pub fn valid_nick(nick: &str) -> bool {
 
     // push_str the rest of the options
     allowed.push_str(DIGIT);
-    allowed.push_str("-");
+    allowed.push('-');
     matches_allowed(&nick[1..], &allowed)
 }
+
+// wildcard matching for nick!user@host masks, '*' is any run of
+// characters (including none) and '?' is exactly one, compared without
+// regard to ASCII case
+pub fn mask_match(mask: &str, target: &str) -> bool {
+    let (mask, target) = (mask.as_bytes(), target.as_bytes());
+    let (mut m, mut t) = (0, 0);
+    // where to resume if what follows the last '*' stops matching
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < target.len() {
+        if m < mask.len() && mask[m] == b'*' {
+            backtrack = Some((m, t));
+            m += 1;
+        } else if m < mask.len() && (mask[m] == b'?' || mask[m].eq_ignore_ascii_case(&target[t])) {
+            m += 1;
+            t += 1;
+        } else if let Some((star, star_t)) = backtrack {
+            // let the '*' swallow one more character and try again
+            m = star + 1;
+            t = star_t + 1;
+            backtrack = Some((star, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+    mask[m..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mask_match_literal() {
+        assert!(mask_match("alice!alice@host", "alice!alice@host"));
+        assert!(mask_match("ALICE!alice@HOST", "alice!Alice@host"));
+        assert!(!mask_match("alice!alice@host", "alice!alice@hos"));
+        assert!(!mask_match("alice!alice@hos", "alice!alice@host"));
+    }
+
+    #[test]
+    fn mask_match_question_mark() {
+        assert!(mask_match("b?b!*@*", "bob!bob@host"));
+        assert!(!mask_match("b?b!*@*", "bb!bob@host"));
+        assert!(!mask_match("b?b!*@*", "boob!bob@host"));
+    }
+
+    #[test]
+    fn mask_match_star() {
+        assert!(mask_match("*", ""));
+        assert!(mask_match("*", "anything"));
+        assert!(mask_match("*!*@*", "bob!bob@host"));
+        assert!(mask_match("bob*", "bob"));
+        assert!(mask_match("**b**", "b"));
+        assert!(!mask_match("*!*@*", "bob@host"));
+        assert!(!mask_match("", "bob"));
+        assert!(mask_match("", ""));
+    }
+
+    #[test]
+    fn mask_match_backtracks() {
+        /* the first "ab" the * stops at is the wrong one */
+        assert!(mask_match("*ab", "aab"));
+        assert!(mask_match("*ab*ab", "abxabyabab"));
+        assert!(mask_match("*.example.net", "a.b.example.net"));
+        assert!(mask_match("*a?c*", "abaxc"));
+        assert!(!mask_match("*ab*ab", "abxab-"));
+        assert!(!mask_match("*.example.net", "example.net"));
+    }
+
+    #[test]
+    fn mask_match_backslash_is_literal() {
+        /* nicks can have \ in them, so there's no escaping - a \ only
+         * matches a \, and * and ? stay wildcards after one */
+        assert!(mask_match("a\\b!*@*", "a\\b!u@h"));
+        assert!(!mask_match("a\\b!*@*", "ab!u@h"));
+        assert!(mask_match("a\\*", "a\\anything"));
+        assert!(!mask_match("a\\*", "a*"));
+        assert!(mask_match("a\\?", "a\\x"));
+    }
+
+    #[test]
+    fn fold_ascii() {
+        let ascii = CaseMapping::Ascii;
+        assert_eq!(ascii.fold("Alice[]\\^~"), "alice[]\\^~");
+        assert_eq!(ascii.fold("#Chan"), "#chan");
+    }
+
+    #[test]
+    fn fold_rfc1459() {
+        let rfc1459 = CaseMapping::Rfc1459;
+        assert_eq!(rfc1459.fold("Alice[]\\~"), "alice{}|^");
+        assert_eq!(rfc1459.fold("{}|^"), "{}|^");
+        assert_eq!(rfc1459.fold("Foo[x]"), rfc1459.fold("foo{X}"));
+    }
+
+    #[test]
+    fn fold_strict_rfc1459() {
+        let strict = CaseMapping::StrictRfc1459;
+        assert_eq!(strict.fold("Alice[]\\~"), "alice{}|~");
+        assert_ne!(strict.fold("a^"), strict.fold("a~"));
+    }
+
+    #[test]
+    fn fold_leaves_non_ascii_alone() {
+        assert_eq!(CaseMapping::Rfc1459.fold("#Café"), "#café");
+        assert_eq!(CaseMapping::Ascii.fold("ÉCOLE"), "École");
+    }
+
+    #[test]
+    fn truncate_at_limit() {
+        assert_eq!(truncate("hello", 5), "hello");
+        assert_eq!(truncate("hello", 4), "hell");
+        assert_eq!(truncate("hello", 0), "");
+        assert_eq!(truncate("", 3), "");
+    }
+
+    #[test]
+    fn truncate_keeps_utf8_whole() {
+        /* é is two bytes, cutting through it backs off to before it */
+        assert_eq!(truncate("café", 5), "café");
+        assert_eq!(truncate("café", 4), "caf");
+        assert_eq!(truncate("日本", 2), "");
+        assert_eq!(truncate("日本", 3), "日");
+    }
+
+    #[test]
+    fn wrap_short_lines_alone() {
+        assert_eq!(wrap("hello world", 11), vec!["hello world"]);
+        assert_eq!(wrap("", 5), vec![""]);
+    }
+
+    #[test]
+    fn wrap_at_spaces() {
+        assert_eq!(wrap("hello world", 10), vec!["hello", "world"]);
+        assert_eq!(wrap("one two three four", 9), vec!["one two", "three", "four"]);
+        assert_eq!(wrap("hello world", 6), vec!["hello", "world"]);
+        assert_eq!(wrap("hello world", 5), vec!["hello", "world"]);
+    }
+
+    #[test]
+    fn wrap_long_words_split() {
+        assert_eq!(wrap("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        assert_eq!(wrap("ab abcdefgh", 4), vec!["ab", "abcd", "efgh"]);
+        for piece in wrap("日本語のテキスト", 4) {
+            assert!(piece.len() <= 4 && !piece.is_empty());
+        }
+    }
+
+    #[test]
+    fn wrap_narrower_than_a_character() {
+        assert_eq!(wrap("日本", 2), vec!["日", "本"]);
+        assert_eq!(wrap("a日", 1), vec!["a", "日"]);
+    }
+
+    #[test]
+    fn wrap_zero_width_gives_up() {
+        assert_eq!(wrap("hello", 0), vec!["hello"]);
+    }
+}
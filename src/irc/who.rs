@@ -0,0 +1,113 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::irc::chan::Channel;
+use crate::irc::reply::Reply as ircReply;
+use crate::irc::rfc_defs as rfc;
+use crate::irc::User;
+
+/* the WHOX fields we know, in the order a 354 carries them whatever
+ * order they were asked for in */
+pub const WHOX_FIELDS: &str = "tcuihsnfdlaor";
+
+/* WHO <mask> [<flags>[%<fields>[,<token>]]] - the o flag only wants
+ * opers, and a % asks for a 354 with just the fields given instead of
+ * the usual 352, the token being handed back so clients can tell their
+ * queries apart */
+#[derive(Debug, Default, Clone)]
+pub struct WhoQuery {
+    pub mask: String,
+    pub opers_only: bool,
+    fields: Option<String>,
+    token: String,
+}
+
+impl WhoQuery {
+    pub fn parse(mask: &str, options: Option<&str>) -> WhoQuery {
+        let options = options.unwrap_or_default();
+        let (flags, whox) = match options.split_once('%') {
+            Some((flags, whox)) => (flags, Some(whox)),
+            None => (options, None),
+        };
+        let (fields, token) = match whox.map(|whox| whox.split_once(',').unwrap_or((whox, "0"))) {
+            Some((fields, token)) => (Some(fields), token),
+            None => (None, "0"),
+        };
+        WhoQuery {
+            mask: mask.to_string(),
+            opers_only: flags.contains('o'),
+            fields: fields.map(|fields| WHOX_FIELDS.chars().filter(|field| fields.contains(*field)).collect()),
+            token: token.to_string(),
+        }
+    }
+
+    /* a mask that isn't a channel is held up against the nick (going by
+     * the casemapping), username, host, real name and server - "0" is
+     * the RFC's way of asking for everyone, same as "*" */
+    pub fn matches(&self, user: &User) -> bool {
+        if self.mask == "0" {
+            return true;
+        }
+        let irc = &user.irc;
+        rfc::mask_match(&irc.casefold(&self.mask), &irc.casefold(&user.get_nick()))
+            || rfc::mask_match(&self.mask, &user.get_username())
+            || rfc::mask_match(&self.mask, &user.get_host_string())
+            || rfc::mask_match(&self.mask, &user.get_realname())
+            || rfc::mask_match(&self.mask, &user.get_server())
+    }
+
+    /* the channel is the one the query was for, if it was for one -
+     * people only get to see the address someone connected from if
+     * it's their own, or they're an oper */
+    pub fn reply(&self, viewer: &User, user: &User, chan: Option<&Channel>) -> ircReply {
+        let chan_name = chan.map_or_else(|| "*".to_string(), |chan| chan.get_name());
+        let mut flags = String::from("H");
+        if user.is_oper() {
+            flags.push('*');
+        }
+        if let Some(chan) = chan {
+            flags.push_str(&chan.member_prefixes(&user.get_nick(), viewer.has_cap("multi-prefix")));
+        }
+        let fields = match &self.fields {
+            Some(fields) => fields,
+            None => return ircReply::WhoReply(
+                chan_name,
+                user.get_username(),
+                user.get_host_string(),
+                user.get_server(),
+                user.get_nick(),
+                flags,
+                user.get_realname(),
+            ),
+        };
+        ircReply::WhoSpcRpl(fields.chars().map(|field| match field {
+            't' => self.token.clone(),
+            'c' => chan_name.clone(),
+            'u' => user.get_username(),
+            'i' if viewer.id == user.id || viewer.is_oper() => user.get_ip().to_string(),
+            'i' => "255.255.255.255".to_string(),
+            'h' => user.get_host_string(),
+            's' => user.get_server(),
+            'n' => user.get_nick(),
+            'f' => flags.clone(),
+            'd' => "0".to_string(),
+            'l' => user.get_idle().to_string(),
+            'a' => user.get_account().unwrap_or_else(|| "0".to_string()),
+            'o' => "n/a".to_string(),
+            _ => format!(":{}", user.get_realname()), /* 'r', always last */
+        }).collect())
+    }
+}
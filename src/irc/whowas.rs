@@ -0,0 +1,74 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+use crate::irc::User;
+use std::collections::VecDeque;
+
+/* what's left of someone once they've quit or changed nick */
+#[derive(Debug, Clone)]
+pub struct WhowasEntry {
+    key: String,
+    pub nick: String,
+    pub username: String,
+    pub host: String,
+    pub real_name: String,
+    pub server: String,
+    pub departed: i64,
+}
+
+impl WhowasEntry {
+    /* key is the casefolded nick, which only Core knows how to make */
+    pub fn new(user: &User, key: String, departed: i64) -> Self {
+        WhowasEntry {
+            key,
+            nick: user.get_nick(),
+            username: user.get_username(),
+            host: user.get_host_string(),
+            real_name: user.get_realname(),
+            server: user.get_server(),
+            departed,
+        }
+    }
+}
+
+/* newest at the front, and never more than limits.whowas_size long -
+ * anything older than limits.whowas_expiry is as good as gone, and
+ * gets cleared out the next time something's added */
+#[derive(Debug, Default)]
+pub struct WhowasRing {
+    entries: VecDeque<WhowasEntry>,
+}
+
+impl WhowasRing {
+    pub fn add(&mut self, entry: WhowasEntry, max: usize, expires_before: i64) {
+        self.entries.retain(|old| old.departed >= expires_before);
+        self.entries.push_front(entry);
+        self.entries.truncate(max);
+    }
+
+    /* up to count entries for the nick, newest first, 0 meaning all */
+    pub fn find(&self, key: &str, count: usize, expires_before: i64) -> Vec<WhowasEntry> {
+        let found = self.entries
+            .iter()
+            .filter(|entry| entry.key == key && entry.departed >= expires_before)
+            .cloned();
+        if count > 0 {
+            found.take(count).collect()
+        } else {
+            found.collect()
+        }
+    }
+}
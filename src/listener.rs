@@ -0,0 +1,194 @@
+/* rusty-ircd - an IRC daemon written in Rust
+*  Copyright (C) 2020 Joanna Janet Zaitseva-Doyle <jjadoyle@gmail.com>
+
+*  This program is free software: you can redistribute it and/or modify
+*  it under the terms of the GNU Lesser General Public License as
+*  published by the Free Software Foundation, either version 3 of the
+*  License, or (at your option) any later version.
+
+*  This program is distributed in the hope that it will be useful,
+*  but WITHOUT ANY WARRANTY; without even the implied warranty of
+*  MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+*  GNU Lesser General Public License for more details.
+
+*  You should have received a copy of the GNU Lesser General Public License
+*  along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+extern crate dns_lookup;
+extern crate log;
+extern crate socket2;
+extern crate tokio;
+extern crate tokio_native_tls;
+use crate::client::{run_client_handler, run_write_task, Host, GenError, LinkStats};
+use crate::config::{Class, Listen};
+use crate::io::{ReadHalfWrap, WriteHalfWrap};
+use crate::irc::{shutdown_reason, Core};
+use dns_lookup::lookup_addr;
+use log::{debug, warn};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io::Error as ioError;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use tokio::io::split;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+use tokio_native_tls::TlsAcceptor;
+
+/* one of these per [[listen]] block - the TLS acceptor and the class
+ * live in Core and are fetched for each new connection, that way REHASH
+ * can swap them without us having to know about it */
+#[derive(Debug)]
+pub struct Listener {
+    server: TcpListener,
+    address: String,
+    tls: bool,
+}
+
+impl Listener {
+    pub fn bind(listen: &Listen) -> Result<Listener, ioError> {
+        /* config validation has already made sure this parses */
+        let addr = listen.address.parse::<SocketAddr>()
+            .expect("listen address should have been validated");
+        Ok(Listener {
+            server: bind_socket(addr, listen.v6_only, listen.backlog)?,
+            address: listen.address.clone(),
+            tls: listen.tls,
+        })
+    }
+}
+
+/* go via socket2 so we get a say in IPV6_V6ONLY and the backlog,
+ * neither of which tokio's TcpListener::bind() lets us touch */
+fn bind_socket(addr: SocketAddr, v6_only: Option<bool>, backlog: i32) -> Result<TcpListener, ioError> {
+    let domain = if addr.is_ipv6() { Domain::ipv6() } else { Domain::ipv4() };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6_only.unwrap_or(false))?;
+    }
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(backlog)?;
+    let std_listener = socket.into_tcp_listener();
+    std_listener.set_nonblocking(true)?;
+    TcpListener::from_std(std_listener)
+}
+
+fn get_host(ip_addr: IpAddr) -> Result<Host, ioError> {
+    match lookup_addr(&ip_addr) {
+        Ok(h) => Ok(Host::Hostname(h)),
+        Err(_) => Ok(Host::HostAddr(ip_addr)),
+    }
+}
+
+/* refuse new connections outright once we're at the configured limit,
+ * rather than letting them register and then kicking them */
+fn server_full(irc: &Core, class: &Class) -> bool {
+    if irc.get_n_clients() >= irc.get_limits().max_clients {
+        debug!("max_clients reached, dropping new connection");
+        true
+    } else if let Some(max) = class.max_clients {
+        let full = irc.get_n_clients_in_class(&class.name) >= max;
+        if full {
+            debug!("class {} is full, dropping new connection", class.name);
+        }
+        full
+    } else {
+        false
+    }
+}
+
+pub async fn run_listener(listener: Listener, irc: Arc<Core>) -> Result<(), GenError> {
+    debug!("listening on {}", listener.address);
+    let mut shutdown = irc.shutdown_watch();
+    loop {
+        let (socket, _) = tokio::select! {
+            res = listener.server.accept() => res?,
+            _ = shutdown_reason(&mut shutdown) => {
+                debug!("no longer listening on {}", listener.address);
+                return Ok(());
+            },
+        };
+        let irc = Arc::clone(&irc);
+        let acceptor = if listener.tls {
+            match irc.get_acceptor(&listener.address) {
+                Some(acceptor) => Some(acceptor),
+                None => {
+                    warn!("no TLS acceptor for {}, dropping connection", listener.address);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        let class = irc.get_listen_class(&listener.address);
+        let address = listener.address.clone();
+        tokio::spawn(async move {
+            if let Err(err) = accept_client(socket, irc, acceptor, class, address).await {
+                debug!("failed to set up new connection: {}", err);
+            }
+        });
+    }
+}
+
+async fn accept_client(
+    sock: TcpStream,
+    irc: Arc<Core>,
+    acceptor: Option<Arc<TlsAcceptor>>,
+    class: Class,
+    address: String,
+) -> Result<(), GenError> {
+    if server_full(&irc, &class) {
+        return Ok(());
+    }
+    let drain_guard = match irc.get_drain_guard() {
+        Some(guard) => guard,
+        None => return Ok(()), /* shutting down */
+    };
+    let id = irc.assign_id();
+    let ip_address = match sock.peer_addr()?.ip() {
+        /* dual-stack sockets see IPv4 peers as ::ffff:a.b.c.d */
+        IpAddr::V6(addr) => addr.to_ipv4_mapped().map_or(IpAddr::V6(addr), IpAddr::V4),
+        addr => addr,
+    };
+    /* a peer can stall us in the DNS lookup or the TLS handshake, and
+     * until they're through they aren't a client anyone can time out,
+     * so both get the class's registration timeout between them */
+    let deadline = Instant::now() + Duration::from_secs(class.register_timeout);
+    let timed_out = || GenError::Quit(String::from("Connection setup timed out"));
+    let lookup = task::spawn_blocking(move || get_host(ip_address));
+    /* one ? each for the timeout, a JoinError and the lookup itself */
+    let host = time::timeout_at(deadline, lookup).await.map_err(|_| timed_out())???;
+    let (read, write) = match acceptor {
+        Some(acceptor) => {
+            let stream = time::timeout_at(deadline, acceptor.accept(sock)).await.map_err(|_| timed_out())??;
+            let (read, write) = split(stream);
+            (ReadHalfWrap::Encrypted(read), WriteHalfWrap::Encrypted(write))
+        },
+        None => {
+            let (read, write) = split(sock);
+            (ReadHalfWrap::ClearText(read), WriteHalfWrap::ClearText(write))
+        },
+    };
+    let (tx, rx) = mpsc::channel(class.sendq);
+    let stats = Arc::new(Mutex::new(LinkStats::default()));
+    let write_stats = Arc::clone(&stats);
+    tokio::spawn(async move {
+        let res = run_write_task(write, rx, write_stats).await;
+        drop(drain_guard);
+        res
+    });
+    tokio::spawn(run_client_handler(
+        id,
+        host,
+        ip_address,
+        irc,
+        tx,
+        read,
+        class.name,
+        address,
+        stats,
+    ));
+    Ok(())
+}
@@ -14,126 +14,158 @@
 *  You should have received a copy of the GNU Lesser General Public License
 *  along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-extern crate dns_lookup;
 extern crate log;
 extern crate tokio;
 extern crate tokio_native_tls;
 pub mod irc;
 pub mod client;
+pub mod config;
 pub mod io;
+pub mod listener;
 pub mod parser;
-use crate::client::{run_client_handler, run_write_task, Host, GenError};
-use crate::io::{ReadHalfWrap, WriteHalfWrap};
+use crate::config::Config;
 use crate::irc::Core;
-use dns_lookup::lookup_addr;
-use std::fs::File;
+use crate::listener::{run_listener, Listener};
+use log::{error, info, warn};
+use std::env;
 use std::io::Error as ioError;
-use std::io::Read;
-use std::net::IpAddr;
+use std::process;
 use std::sync::Arc;
-use tokio::io::split;
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::mpsc;
-use tokio::task;
-use tokio_native_tls::TlsAcceptor;
-use tokio_native_tls::native_tls::Identity;
-use tokio_native_tls::native_tls::TlsAcceptor as NativeTlsAcc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 
-pub const USER_MODES: &str = "";
-pub const CHAN_MODES: &str = "+o";
+pub const USER_MODES: &str = "io";
+pub const CHAN_MODES: &str = "AIORabehiklmnopqrstv";
+pub const DEFAULT_CONFIG: &str = "ircd.toml";
 
-fn get_host(ip_addr: IpAddr) -> Result<Host, ioError> {
-    match lookup_addr(&ip_addr) {
-        Ok(h) => Ok(Host::Hostname(h)),
-        Err(_) => Ok(Host::HostAddr(ip_addr)),
+/* SIGHUP does the same as an oper's REHASH, just with the report
+ * going to the log instead */
+async fn rehash_on_sighup(irc: Arc<Core>) -> Result<(), ioError> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, rehashing {}", irc.get_config_path());
+        irc.rehash().await.log();
     }
-}
-
-async fn plaintext_socket(sock: TcpStream, irc: Arc<Core>) -> Result<(), GenError> {
-    let id = irc.assign_id();
-    /* Two ? required, one expects a potential JoinError, the second ?
-     * decomposes to give Host or an ioError - may need some additional error
-     * composition to deal with the possible JoinError... */
-    let ip_address = sock.peer_addr()?.ip();
-    let host = task::spawn_blocking(move || get_host(ip_address)).await??;
-    let (tx, rx) = mpsc::channel(32);
-    let (read, write) = split(sock);
-    tokio::spawn(run_write_task(WriteHalfWrap::ClearText(write), rx));
-    tokio::spawn(run_client_handler(
-        id,
-        host,
-        irc,
-        tx,
-        ReadHalfWrap::ClearText(read),
-    ));
     Ok(())
 }
 
-async fn plain_listen(server: TcpListener, irc_core: Arc<Core>) -> Result<(), GenError> {
+/* timed bans come off on their own, nobody's going to remember to
+ * take them off by hand, and +r safe channels get reopped - a minute
+ * or so of slack is fine for both */
+async fn channel_housekeeping(irc: Arc<Core>) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(30));
     loop {
-        let (socket, _) = server.accept().await?;
-        tokio::spawn(plaintext_socket(socket, Arc::clone(&irc_core)));
+        ticks.tick().await;
+        irc.expire_masks().await;
+        irc.reop_chans().await;
     }
 }
 
-async fn process_socket(sock: TcpStream, irc: Arc<Core>, acceptor: Arc<TlsAcceptor>) -> Result<(), GenError> {
-    let id = irc.assign_id();
-    /* Two ? required, one expects a potential JoinError, the second ?
-     * decomposes to give Host or an ioError - may need some additional error
-     * composition to deal with the possible JoinError... */
-    let ip_address = sock.peer_addr()?.ip();
-    let host = task::spawn_blocking(move || get_host(ip_address)).await??;
-    let (tx, rx) = mpsc::channel(32);
-    let tls_stream = acceptor.accept(sock).await?;
-    let (read, write) = split(tls_stream);
-    tokio::spawn(run_write_task(WriteHalfWrap::Encrypted(write), rx));
-    tokio::spawn(run_client_handler(
-        id,
-        host,
-        irc,
-        tx,
-        ReadHalfWrap::Encrypted(read),
-    ));
-    Ok(())
+/* SIGTERM or SIGINT start a graceful shutdown, a second one means
+ * whoever's at the terminal doesn't want to wait for it */
+async fn shutdown_on_signal(irc: Arc<Core>) -> Result<(), ioError> {
+    let mut terms = signal(SignalKind::terminate())?;
+    let mut ints = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terms.recv() => info!("SIGTERM received"),
+        _ = ints.recv() => info!("SIGINT received"),
+    }
+    irc.shutdown(&irc.get_shutdown_config().reason);
+    tokio::select! {
+        _ = terms.recv() => (),
+        _ = ints.recv() => (),
+    }
+    warn!("second signal received, exiting without waiting for clients");
+    process::exit(1);
+}
+
+fn usage() -> ! {
+    eprintln!("usage: {} [--check-config] [config file (default {})]", env!("CARGO_PKG_NAME"), DEFAULT_CONFIG);
+    process::exit(2);
 }
 
 #[tokio::main]
 pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let version = env!("CARGO_PKG_NAME").to_string() + ", version: " + env!("CARGO_PKG_VERSION");
+    let version = format!("{}-{}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
     env_logger::init();
 
-    // is this even necessary?
-    let server_host = if let Ok(ip) = "127.0.1.1".parse::<IpAddr>() {
-        if let Host::Hostname(h) = task::spawn_blocking(move ||get_host(ip)).await?? {
-            h
-        } else {
-            "localhost".to_string()
+    let mut check_only = false;
+    let mut config_path = None;
+    for arg in env::args().skip(1) {
+        match &arg[..] {
+            "--check-config" => check_only = true,
+            _ if config_path.is_none() && !arg.starts_with('-') => config_path = Some(arg),
+            _ => usage(),
         }
-    } else {
-        "localhost".to_string()
-    };
-    let irc_core = Core::new(server_host, version);
-
-    // encryption key stuff
-    let mut file = File::open("identity.pfx").unwrap();
-    let mut identity = vec![];
-    file.read_to_end(&mut identity).unwrap();
-    let identity = Identity::from_pkcs12(&identity, "password").expect("failed to get identity, check password?");
+    }
+    let config_path = config_path.unwrap_or_else(|| DEFAULT_CONFIG.to_string());
 
-    // start raw socket listeners
-    let plain_listener = TcpListener::bind("127.0.1.1:6667").await?;
-    let listener = TcpListener::bind("127.0.1.1:6697").await?;
-    
-    // spawn routine to deal with plaintext clients
-    tokio::spawn(plain_listen(plain_listener, Arc::clone(&irc_core)));
+    /* loading the TLS identities is part of checking the config, a wrong
+     * password should be caught by --check-config too */
+    let (config, acceptors) = match Config::load(&config_path).and_then(|config| {
+        let acceptors = config.listeners
+            .iter()
+            .map(|listen| config.tls_acceptor(listen))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((config, acceptors))
+    }) {
+        Ok(pair) => pair,
+        Err(err) => {
+            eprintln!("{}: {}", env!("CARGO_PKG_NAME"), err);
+            process::exit(1);
+        }
+    };
+    if check_only {
+        /* a missing MOTD isn't worth refusing to start over, but say so */
+        for listen in config.listeners.iter() {
+            if let Err(err) = config.load_motd(listen) {
+                eprintln!("{}: warning: {}", env!("CARGO_PKG_NAME"), err);
+            }
+        }
+        println!("{}: configuration OK", config_path);
+        return Ok(());
+    }
+    let irc_core = Core::new(&config_path, config.clone(), version);
 
-    // first create the non-async TlsAcceptor
-    let acceptor = NativeTlsAcc::new(identity).unwrap();
+    // bind everything before we accept anything, one task per listener
+    let mut listeners = Vec::new();
+    for (listen, acceptor) in config.listeners.iter().zip(acceptors) {
+        if let Some(acceptor) = acceptor {
+            irc_core.set_acceptor(&listen.address, acceptor);
+        }
+        match config.load_motd(listen) {
+            Ok(motd) => irc_core.set_motd(&listen.address, motd),
+            Err(err) => warn!("{}, {} will have no MOTD", err, listen.address),
+        }
+        match Listener::bind(listen) {
+            Ok(listener) => listeners.push(listener),
+            Err(err) => {
+                eprintln!("{}: can't listen on {}: {}", env!("CARGO_PKG_NAME"), listen.address, err);
+                process::exit(1);
+            }
+        }
+    }
+    tokio::spawn(rehash_on_sighup(Arc::clone(&irc_core)));
+    tokio::spawn(shutdown_on_signal(Arc::clone(&irc_core)));
+    tokio::spawn(channel_housekeeping(Arc::clone(&irc_core)));
+    for listener in listeners {
+        let irc = Arc::clone(&irc_core);
+        tokio::spawn(async move {
+            if let Err(err) = run_listener(listener, irc).await {
+                error!("listener failed: {}", err);
+            }
+        });
+    }
 
-    // this creates the tokio wrapper
-    let acceptor = Arc::new(TlsAcceptor::from(acceptor));
-    loop {
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(process_socket(socket, Arc::clone(&irc_core), Arc::clone(&acceptor)));
+    /* from here on it's all happening in other tasks, we just wait for
+     * a signal or DIE - the listeners and clients see the same shutdown
+     * and close up themselves, we give them drain_timeout to do so */
+    irc_core.wait_for_shutdown().await;
+    let drain_timeout = irc_core.get_shutdown_config().drain_timeout;
+    if irc_core.drain(Duration::from_secs(drain_timeout)).await {
+        info!("all clients closed, exiting");
+    } else {
+        warn!("gave up waiting for clients after {} seconds", drain_timeout);
     }
+    Ok(())
 }
@@ -91,7 +91,7 @@ pub fn parse_message(message: &str) -> Result<ParsedMsg, ParseError> {
             return Err(ParseError::NoCommand);
         }
         line = vec[1];
-        Some(parse_prefix(&vec[0])?)
+        Some(parse_prefix(vec[0])?)
     } else {
         None
     };